@@ -0,0 +1,104 @@
+//! Types shared by the PollRush canisters so that clients see a single Candid
+//! definition for them.
+
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+pub mod merkle;
+pub mod stable;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PollStatus {
+    Draft,
+    Scheduled,
+    Open,
+    Closed,
+    Cancelled,
+    Archived,
+}
+
+/// Error returned by every fallible canister endpoint. Clients branch on the
+/// variant instead of matching message text.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum PollError {
+    PollNotFound,
+    /// The caller is neither the poll creator nor a canister controller.
+    NotAuthorized,
+    PollClosed { closed_at: u64 },
+    /// The poll is not accepting votes in its current status.
+    PollNotOpen { status: PollStatus },
+    InvalidTransition { from: PollStatus, to: PollStatus },
+    /// `max` is the highest valid option index of the poll.
+    InvalidOption { max: u32 },
+    InsufficientBalance { available: u64 },
+    EmptyTitle,
+    TitleTooLong { max: u32 },
+    DescriptionTooLong { max: u32 },
+    InvalidOptionCount { min: u32, max: u32 },
+    EmptyOption { index: u32 },
+    OptionTooLong { index: u32, max: u32 },
+    /// The option at `index` repeats an earlier option.
+    DuplicateOption { index: u32 },
+    DeadlineInPast,
+    /// `latest` is the furthest deadline currently accepted.
+    DeadlineTooFar { latest: u64 },
+    StartAfterDeadline,
+    InvalidConfig { reason: String },
+    InvalidLegacyPoll { legacy_id: u64, reason: String },
+    /// A stored record could not be decoded.
+    DecodeFailed { version: u8, reason: String },
+    /// The poll's voting mode is inconsistent with its options.
+    InvalidVotingMode { reason: String },
+    /// The ballot selects fewer than `min` or more than `max` options.
+    InvalidChoiceCount { min: u32, max: u32 },
+    /// The choice at `index` of the ballot repeats an earlier choice.
+    DuplicateChoice { index: u32 },
+    /// The requested results are computed when the poll closes.
+    ResultsNotFinal,
+    /// The ballot is malformed for the poll's voting mode.
+    InvalidBallot { reason: String },
+    /// The ballot costs more voice credits than the voter may spend.
+    BudgetExceeded { cost: u64, budget: u64 },
+    /// Locked PPS cannot be released before `until`.
+    StillLocked { until: u64 },
+    /// Credits locked for the poll must stay locked until its `deadline`.
+    LockTooShort { deadline: u64 },
+    /// A call to another canister failed.
+    CanisterCallFailed { reason: String },
+    /// A scale answer must lie from `min` to `max`, a whole number of `step`s
+    /// above `min`.
+    InvalidValue { min: i64, max: i64, step: u64 },
+    /// A free-text answer, or a poll's settings for them, is malformed.
+    InvalidAnswer { reason: String },
+    AnswerTooLong { max: u32 },
+    AnswerNotFound,
+    /// Question `index` of a survey, or the answer to it, failed with `error`.
+    InvalidQuestion { index: u32, error: Box<PollError> },
+    /// A survey question's `show_if` condition is malformed.
+    InvalidCondition { reason: String },
+    /// Survey question `question` is shown and required but was not answered.
+    MissingAnswer { question: u32 },
+    /// The caller already voted; `change_vote` replaces a ballot.
+    AlreadyVoted,
+    /// The caller has no ballot in the poll to change or revoke.
+    NotVoted,
+    /// The poll's vote change policy forbids this change.
+    VoteChangeNotAllowed,
+    /// The revealed option and salt do not hash to the caller's commitment.
+    RevealMismatch,
+    /// Secret ballots could be revealed until `ended_at`.
+    RevealWindowClosed { ended_at: u64 },
+    /// Members and invites only apply to private polls.
+    PollNotPrivate,
+    InviteNotFound,
+    /// The invite could be redeemed until `expired_at`.
+    InviteExpired { expired_at: u64 },
+    /// The invite has been redeemed as many times as it allows.
+    InviteUsedUp,
+    /// At most `max` principals can be added or removed in one call.
+    TooManyMembers { max: u32 },
+    /// The caller did not prove they are on the poll's eligibility list.
+    NotEligible,
+    /// The credit would take a balance or lock past the largest amount.
+    BalanceOverflow,
+}
@@ -0,0 +1,116 @@
+//! SHA-256 Merkle trees over `(principal, weight)` lists, as committed to by
+//! poll eligibility roots.
+//!
+//! Leaves are `SHA-256(0x00 ‖ weight ‖ principal)` with the weight as a
+//! big-endian `u64`, inner nodes `SHA-256(0x01 ‖ lo ‖ hi)` over their children
+//! in byte order, so proofs need no left/right flags, and a node without a
+//! sibling moves up a level unchanged.
+
+use candid::Principal;
+use sha2::{Digest, Sha256};
+
+pub type Hash = [u8; 32];
+
+pub fn leaf_hash(principal: Principal, weight: u64) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([0]);
+    hasher.update(weight.to_be_bytes());
+    hasher.update(principal.as_slice());
+    hasher.finalize().into()
+}
+
+pub fn node_hash(a: &Hash, b: &Hash) -> Hash {
+    let (lo, hi) = if a <= b { (a, b) } else { (b, a) };
+    let mut hasher = Sha256::new();
+    hasher.update([1]);
+    hasher.update(lo);
+    hasher.update(hi);
+    hasher.finalize().into()
+}
+
+/// Builds the root of a list and the proofs of its entries.
+pub struct MerkleTree {
+    /// Leaf hashes first, the root alone last.
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    /// Panics if `entries` is empty, which has no root.
+    pub fn new(entries: &[(Principal, u64)]) -> Self {
+        assert!(!entries.is_empty(), "a Merkle tree needs at least one entry");
+        let leaves = entries.iter().map(|&(principal, weight)| leaf_hash(principal, weight)).collect();
+        let mut levels: Vec<Vec<Hash>> = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let level = levels.last().unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [a, b] => node_hash(a, b),
+                    [a] => *a,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(level);
+        }
+        MerkleTree { levels }
+    }
+
+    pub fn root(&self) -> Hash {
+        self.levels.last().unwrap()[0]
+    }
+
+    /// Sibling hashes from the leaf of the entry at `index` to the root.
+    pub fn proof(&self, index: usize) -> Vec<Hash> {
+        let mut index = index;
+        let mut path = Vec::new();
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(index ^ 1) {
+                path.push(*sibling);
+            }
+            index /= 2;
+        }
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256(parts: &[&[u8]]) -> Hash {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finalize().into()
+    }
+
+    #[test]
+    fn trees_follow_the_hashing_rules() {
+        let entries: Vec<(Principal, u64)> = (1..=3u8)
+            .map(|id| (Principal::from_slice(&[id]), 10 * id as u64))
+            .collect();
+        let leaves: Vec<Hash> = entries.iter()
+            .map(|(principal, weight)| sha256(&[&[0], &weight.to_be_bytes(), principal.as_slice()]))
+            .collect();
+        let node = |a: &Hash, b: &Hash| if a <= b { sha256(&[&[1], a, b]) } else { sha256(&[&[1], b, a]) };
+
+        // The third leaf has no sibling and moves up unchanged.
+        let tree = MerkleTree::new(&entries);
+        let pair = node(&leaves[0], &leaves[1]);
+        assert_eq!(tree.root(), node(&pair, &leaves[2]));
+        assert_eq!(tree.proof(0), vec![leaves[1], leaves[2]]);
+        assert_eq!(tree.proof(2), vec![pair]);
+        assert_eq!(node_hash(&leaves[1], &leaves[0]), pair);
+
+        for (index, &(principal, weight)) in entries.iter().enumerate() {
+            let root = tree.proof(index)
+                .iter()
+                .fold(leaf_hash(principal, weight), |hash, sibling| node_hash(&hash, sibling));
+            assert_eq!(root, tree.root());
+        }
+
+        let single = MerkleTree::new(&entries[..1]);
+        assert_eq!(single.root(), leaves[0]);
+        assert!(single.proof(0).is_empty());
+    }
+}
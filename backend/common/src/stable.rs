@@ -0,0 +1,313 @@
+//! Versioned encoding for records kept in stable memory.
+//!
+//! Every record is stored as a version byte followed by its bincode payload.
+//! Readers dispatch on the version, so adding a field only needs a new
+//! version number and a decoder for the previous layout; old records are
+//! decoded into the current shape on read and re-encoded in the current
+//! version the next time they are written.
+
+use crate::PollError;
+use candid::Principal;
+use ic_stable_structures::{BoundedStorable, Memory, StableBTreeMap, Storable};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::marker::PhantomData;
+
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// Version written by [`Envelope::new`].
+    const VERSION: u8;
+    /// Upper bound on the encoded payload, excluding the version byte.
+    const MAX_SIZE: u32;
+
+    /// Decodes a payload written with `version`. The default only understands
+    /// the current version; types override it to keep reading older layouts.
+    fn decode_version(version: u8, payload: &[u8]) -> Result<Self, PollError> {
+        if version == Self::VERSION {
+            decode_payload(version, payload)
+        } else {
+            Err(unknown_version(version))
+        }
+    }
+
+    /// Recognizes records written before envelopes were introduced, which
+    /// carry no version byte. Returns `None` for enveloped bytes.
+    fn decode_unversioned(_bytes: &[u8]) -> Option<Result<Self, PollError>> {
+        None
+    }
+}
+
+/// Error for a record written with a version the reader does not know.
+pub fn unknown_version(version: u8) -> PollError {
+    PollError::DecodeFailed {
+        version,
+        reason: "unknown version".to_string(),
+    }
+}
+
+/// Deserializes a bincode payload, reporting failures as `DecodeFailed`.
+pub fn decode_payload<T: DeserializeOwned>(version: u8, payload: &[u8]) -> Result<T, PollError> {
+    bincode::deserialize(payload).map_err(|err| PollError::DecodeFailed {
+        version,
+        reason: err.to_string(),
+    })
+}
+
+/// Raw stored form of a `T`. Loading an envelope never fails; decoding it
+/// with [`Envelope::decode`] reports a broken record as an error instead of
+/// trapping the canister.
+pub struct Envelope<T> {
+    bytes: Vec<u8>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Versioned> Envelope<T> {
+    pub fn new(value: &T) -> Self {
+        let mut bytes = vec![T::VERSION];
+        bincode::serialize_into(&mut bytes, value).expect("Failed to encode record");
+        Envelope {
+            bytes,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn decode(&self) -> Result<T, PollError> {
+        if let Some(decoded) = T::decode_unversioned(&self.bytes) {
+            return decoded;
+        }
+        match self.bytes.split_first() {
+            Some((&version, payload)) => T::decode_version(version, payload),
+            None => Err(PollError::DecodeFailed {
+                version: 0,
+                reason: "empty record".to_string(),
+            }),
+        }
+    }
+}
+
+impl<T> Storable for Envelope<T> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Envelope {
+            bytes: bytes.into_owned(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Versioned> BoundedStorable for Envelope<T> {
+    const MAX_SIZE: u32 = T::MAX_SIZE + 1;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Length of the longest principal.
+const PRINCIPAL_BYTES: usize = 29;
+
+/// A principal used as (part of) a stable map key.
+///
+/// Encoded as a length byte followed by the principal's bytes zero-padded to
+/// 29, so every key takes exactly 30 bytes. That is the same order
+/// `Principal` itself sorts in (length first, then bytes), which keeps range
+/// scans over composite keys such as `(poll_id, voter)` contiguous.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PrincipalKey(pub Principal);
+
+impl Default for PrincipalKey {
+    fn default() -> Self {
+        // Use the anonymous principal as default
+        PrincipalKey(Principal::anonymous())
+    }
+}
+
+impl PrincipalKey {
+    /// The smallest possible key, used as the lower bound of range scans.
+    pub fn min() -> Self {
+        PrincipalKey(Principal::management_canister())
+    }
+}
+
+impl Storable for PrincipalKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let principal = self.0.as_slice();
+        let mut bytes = vec![0; Self::MAX_SIZE as usize];
+        bytes[0] = principal.len() as u8;
+        bytes[1..=principal.len()].copy_from_slice(principal);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let len = bytes[0] as usize;
+        PrincipalKey(Principal::from_slice(&bytes[1..=len]))
+    }
+}
+
+impl BoundedStorable for PrincipalKey {
+    const MAX_SIZE: u32 = 1 + PRINCIPAL_BYTES as u32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// A principal key as stored before [`PrincipalKey`]: its bincode encoding, a
+/// `u64` length followed by the bytes, in a slot of 29 bytes. Only read, to
+/// move old maps over to the current key encoding with [`rekey`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LegacyPrincipalKey(pub Principal);
+
+impl Default for LegacyPrincipalKey {
+    fn default() -> Self {
+        LegacyPrincipalKey(Principal::anonymous())
+    }
+}
+
+impl Storable for LegacyPrincipalKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(bincode::serialize(&self.0).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        // Keys in composite keys come padded to the full slot.
+        LegacyPrincipalKey(bincode::deserialize(&bytes).unwrap())
+    }
+}
+
+impl BoundedStorable for LegacyPrincipalKey {
+    const MAX_SIZE: u32 = PRINCIPAL_BYTES as u32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// Moves every entry of the map in `memory`, keyed with [`LegacyPrincipalKey`]s,
+/// into `target` under the key `convert` returns, and empties the old map.
+/// A memory that was never written holds no map and is left alone.
+pub fn rekey<M, L, K, V>(memory: M, target: &mut StableBTreeMap<K, V, M>, convert: impl Fn(L) -> K)
+where
+    M: Memory,
+    L: BoundedStorable + Ord + Clone,
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    if memory.size() == 0 {
+        return;
+    }
+    let legacy: StableBTreeMap<L, V, M> = StableBTreeMap::init(memory);
+    for (key, value) in legacy.iter() {
+        target.insert(convert(key), value);
+    }
+    legacy.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic xorshift generator, so failures reproduce.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn principal(&mut self) -> Principal {
+            let len = (self.next() % (PRINCIPAL_BYTES as u64 + 1)) as usize;
+            // Small alphabet so equal prefixes and trailing zeros are common.
+            let bytes: Vec<u8> = (0..len).map(|_| (self.next() % 3) as u8).collect();
+            Principal::from_slice(&bytes)
+        }
+    }
+
+    fn samples() -> Vec<Principal> {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let mut principals: Vec<_> = (0..500).map(|_| rng.principal()).collect();
+        principals.extend([
+            Principal::management_canister(),
+            Principal::anonymous(),
+            Principal::from_slice(&[0xff; PRINCIPAL_BYTES]),
+        ]);
+        principals
+    }
+
+    #[test]
+    fn principal_keys_round_trip_at_fixed_width() {
+        for principal in samples() {
+            let key = PrincipalKey(principal);
+            let bytes = key.to_bytes();
+            assert_eq!(bytes.len(), PrincipalKey::MAX_SIZE as usize);
+            assert_eq!(PrincipalKey::from_bytes(bytes), key);
+        }
+    }
+
+    #[test]
+    fn principal_key_bytes_sort_like_keys() {
+        let principals = samples();
+        for a in &principals {
+            for b in &principals {
+                let (a, b) = (PrincipalKey(*a), PrincipalKey(*b));
+                assert_eq!(a.to_bytes().cmp(&b.to_bytes()), a.cmp(&b), "{a:?} vs {b:?}");
+            }
+            assert!(PrincipalKey::min() <= PrincipalKey(*a));
+        }
+    }
+
+    #[test]
+    fn vote_keys_round_trip_and_sort_like_tuples() {
+        let mut rng = Rng(42);
+        let keys: Vec<(u64, PrincipalKey)> = samples()
+            .into_iter()
+            .map(|principal| (rng.next() % 4, PrincipalKey(principal)))
+            .collect();
+
+        const { assert!(<(u64, PrincipalKey)>::IS_FIXED_SIZE) };
+        for a in &keys {
+            let bytes = a.to_bytes();
+            assert_eq!(bytes.len(), 8 + PrincipalKey::MAX_SIZE as usize);
+            assert_eq!(<(u64, PrincipalKey)>::from_bytes(bytes), *a);
+            for b in &keys {
+                assert_eq!(a.to_bytes().cmp(&b.to_bytes()), a.cmp(b), "{a:?} vs {b:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_keys_move_to_the_current_encoding() {
+        use ic_stable_structures::VectorMemory;
+
+        let (old, new) = (VectorMemory::default(), VectorMemory::default());
+        let principals = [
+            Principal::from_slice(&[2]),
+            Principal::from_slice(&[1; 21]),
+            Principal::anonymous(),
+        ];
+        let mut legacy: StableBTreeMap<(u64, LegacyPrincipalKey), u64, _> = StableBTreeMap::init(old.clone());
+        for (poll_id, principal) in principals.iter().enumerate() {
+            legacy.insert((poll_id as u64, LegacyPrincipalKey(*principal)), poll_id as u64 * 10);
+        }
+        // The baseline bound: a 21-byte principal fills the 29-byte slot.
+        assert_eq!(LegacyPrincipalKey(principals[1]).to_bytes().len(), PRINCIPAL_BYTES);
+
+        let mut current: StableBTreeMap<(u64, PrincipalKey), u64, _> = StableBTreeMap::init(new);
+        rekey(old.clone(), &mut current, |(poll_id, LegacyPrincipalKey(principal))| {
+            (poll_id, PrincipalKey(principal))
+        });
+        let moved: Vec<_> = current.iter().collect();
+        let expected: Vec<_> = principals.iter()
+            .enumerate()
+            .map(|(poll_id, principal)| ((poll_id as u64, PrincipalKey(*principal)), poll_id as u64 * 10))
+            .collect();
+        assert_eq!(moved, expected);
+
+        let emptied: StableBTreeMap<(u64, LegacyPrincipalKey), u64, _> = StableBTreeMap::init(old);
+        assert!(emptied.is_empty());
+
+        // A memory never written to is not turned into a map.
+        let unused = VectorMemory::default();
+        rekey(unused.clone(), &mut current, |(poll_id, LegacyPrincipalKey(principal))| {
+            (poll_id, PrincipalKey(principal))
+        });
+        assert_eq!((unused.size(), current.len()), (0, 3));
+    }
+}
@@ -0,0 +1,157 @@
+//! Free-text answers submitted alongside a ballot, such as an "other" option
+//! or a "tell us why".
+
+use super::*;
+
+/// Upper bound on `FreeText::max_len`, which keeps an answer inside
+/// `Answer::MAX_SIZE`.
+const MAX_ANSWER_LEN: u32 = 2_000;
+
+/// How a poll collects a free-text answer with each ballot.
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FreeText {
+    /// Longest accepted answer, in characters.
+    max_len: u32,
+    /// Answers are shown only to the poll's managers until it closes.
+    pub(crate) hidden_until_closed: bool,
+}
+
+impl FreeText {
+    pub(crate) fn validate(self) -> Result<(), PollError> {
+        if self.max_len == 0 || self.max_len > MAX_ANSWER_LEN {
+            return Err(PollError::InvalidAnswer {
+                reason: format!("max_len must be from 1 to {}", MAX_ANSWER_LEN),
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check(self, text: &str) -> Result<(), PollError> {
+        if text.trim().is_empty() {
+            return Err(PollError::InvalidAnswer { reason: "answers must not be empty".to_string() });
+        }
+        if text.chars().count() > self.max_len as usize {
+            return Err(PollError::AnswerTooLong { max: self.max_len });
+        }
+        Ok(())
+    }
+}
+
+/// A voter's free-text answer, stored apart from the ballot so votes stay
+/// small.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub(crate) struct Answer {
+    pub(crate) voter: Principal,
+    text: String,
+    answered_at: u64,
+    /// Set by a poll manager to withhold the answer from everyone else.
+    pub(crate) hidden: bool,
+}
+
+impl Versioned for Answer {
+    const VERSION: u8 = 1;
+    // A character takes at most 4 bytes of UTF-8.
+    const MAX_SIZE: u32 = 4 * MAX_ANSWER_LEN + 128;
+}
+
+impl Answer {
+    pub(crate) fn new(voter: Principal, text: String, now: u64) -> Self {
+        Answer {
+            voter,
+            text,
+            answered_at: now,
+            hidden: false,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct AnswersPage {
+    pub(crate) answers: Vec<Answer>,
+    pub(crate) next_cursor: Option<Principal>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_are_checked_against_the_poll_limit() {
+        let free_text = FreeText { max_len: 5, hidden_until_closed: false };
+        assert!(free_text.validate().is_ok());
+        assert!(free_text.check("héllo").is_ok());
+        assert_eq!(free_text.check("hello!"), Err(PollError::AnswerTooLong { max: 5 }));
+        assert!(matches!(free_text.check("  "), Err(PollError::InvalidAnswer { .. })));
+
+        let unbounded = FreeText { max_len: MAX_ANSWER_LEN + 1, hidden_until_closed: true };
+        assert!(matches!(unbounded.validate(), Err(PollError::InvalidAnswer { .. })));
+    }
+
+    #[test]
+    fn answers_follow_the_ballot_visibility() {
+        let alice = Principal::from_slice(&[1]);
+        let creator = Principal::from_slice(&[100]);
+        let answered_poll = |id, ballot_visibility| {
+            let poll = Poll {
+                free_text: Some(FreeText { max_len: 50, hidden_until_closed: false }),
+                ballot_visibility,
+                ..crate::tests::open_poll(id, VoteChangePolicy::Immutable)
+            };
+            insert_poll(poll, 0);
+            let args = VoteArgs {
+                answer: Some("Because".to_string()),
+                ..crate::tests::ballot(id, 0)
+            };
+            cast_vote(args, alice, 5, None, None, false).unwrap();
+        };
+        let listed = |id, caller| answers_page(id, None, 10, caller, 6).map(|page| page.answers.len());
+
+        answered_poll(50, BallotVisibility::Public);
+        assert_eq!(listed(50, alice), Ok(1));
+        // A broken answer is left out of the page.
+        let garbage = Envelope::from_bytes(Cow::Owned(vec![0xff, 1, 2]));
+        let bob = PrincipalKey(Principal::from_slice(&[2]));
+        ANSWERS.with(|answers| answers.borrow_mut().insert((50, bob), garbage));
+        assert_eq!(listed(50, alice), Ok(1));
+
+        answered_poll(51, BallotVisibility::CreatorOnly);
+        assert_eq!(listed(51, creator), Ok(1));
+        assert_eq!(listed(51, alice), Err(PollError::NotAuthorized));
+
+        answered_poll(52, BallotVisibility::AggregateOnly);
+        assert_eq!(listed(52, creator), Err(PollError::NotAuthorized));
+        assert_eq!(listed(52, alice), Err(PollError::NotAuthorized));
+    }
+
+    #[test]
+    fn answers_follow_the_results_visibility() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let creator = Principal::from_slice(&[100]);
+        let answered_poll = |id, results_visibility| {
+            let poll = Poll {
+                free_text: Some(FreeText { max_len: 50, hidden_until_closed: false }),
+                results_visibility,
+                deadline: Some(10),
+                ..crate::tests::open_poll(id, VoteChangePolicy::Immutable)
+            };
+            insert_poll(poll, 0);
+            let args = VoteArgs {
+                answer: Some("Because".to_string()),
+                ..crate::tests::ballot(id, 0)
+            };
+            cast_vote(args, alice, 5, None, None, false).unwrap();
+        };
+        let listed = |id, caller, now| answers_page(id, None, 10, caller, now).map(|page| page.answers.len());
+
+        answered_poll(53, ResultsVisibility::AfterVoting);
+        assert_eq!(listed(53, alice, 6), Ok(1));
+        assert_eq!(listed(53, bob, 6), Err(PollError::NotVoted));
+        assert_eq!(listed(53, creator, 6), Ok(1));
+
+        answered_poll(54, ResultsVisibility::AfterClose);
+        assert_eq!(listed(54, alice, 6), Err(PollError::ResultsNotFinal));
+        assert_eq!(listed(54, creator, 6), Ok(1));
+        assert_eq!(listed(54, bob, 11), Ok(1));
+    }
+}
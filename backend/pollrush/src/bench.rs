@@ -0,0 +1,73 @@
+//! Instruction-count benchmarks for vote lookups.
+//!
+//! Only compiled with `--features bench`. Build the wasm with
+//! `cargo build -p pollrush --target wasm32-unknown-unknown --release --features bench`,
+//! install it on a local replica, seed a few polls with `bench_seed_votes` and
+//! compare the counters reported by `bench_vote_lookup`.
+
+use super::*;
+use ic_cdk::api::performance_counter;
+
+/// Instructions executed so far in the current message.
+const INSTRUCTION_COUNTER: u32 = 0;
+
+#[derive(CandidType, Deserialize)]
+struct VoteLookupBench {
+    votes_in_map: u64,
+    matched: u64,
+    full_scan_instructions: u64,
+    range_scan_instructions: u64,
+}
+
+/// Inserts `count` synthetic votes into `poll_id`, bypassing validation and
+/// tallies, so lookups can be measured against a large `VOTES` map.
+#[ic_cdk::update]
+fn bench_seed_votes(poll_id: u64, count: u64) {
+    let now = time();
+
+    VOTES.with(|votes| {
+        let mut votes = votes.borrow_mut();
+        for i in 0..count {
+            let voter = Principal::from_slice(&i.to_be_bytes());
+            let vote = Vote {
+                poll_id,
+                voter,
+                choices: vec![0],
+                votes: Vec::new(),
+                value: None,
+                voted_at: now,
+                revision: 0,
+                weight: 1,
+            };
+            votes.insert((poll_id, PrincipalKey(voter)), Envelope::new(&vote));
+        }
+    });
+}
+
+/// Counts the votes of `poll_id` twice: once with the full-map filter that
+/// `get_votes` used to perform and once with the bounded range scan.
+#[ic_cdk::query]
+fn bench_vote_lookup(poll_id: u64) -> VoteLookupBench {
+    VOTES.with(|votes| {
+        let votes = votes.borrow();
+
+        let start = performance_counter(INSTRUCTION_COUNTER);
+        let full_scan = votes.iter()
+            .filter(|((id, _), _)| *id == poll_id)
+            .count();
+        let full_scan_instructions = performance_counter(INSTRUCTION_COUNTER) - start;
+
+        let start = performance_counter(INSTRUCTION_COUNTER);
+        let range_scan = votes.range(poll_votes_range(poll_id, None)).count();
+        let range_scan_instructions = performance_counter(INSTRUCTION_COUNTER) - start;
+
+        debug_assert_eq!(full_scan, range_scan);
+
+        VoteLookupBench {
+            votes_in_map: votes.len(),
+            matched: range_scan as u64,
+            full_scan_instructions,
+            range_scan_instructions,
+        }
+    })
+}
@@ -0,0 +1,437 @@
+//! Controller-configurable limits and the validation applied to new polls.
+
+use super::*;
+use survey::MAX_QUESTIONS;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Upper bound on `PollConfig::allowed_ledgers`, which keeps the config inside
+/// its `MAX_SIZE`.
+const MAX_ALLOWED_LEDGERS: usize = 16;
+
+/// Upper bound on `PollConfig::max_options`, which keeps a poll's per-option
+/// counters inside `Tally::MAX_SIZE`.
+const MAX_OPTIONS: u32 = 1_000;
+
+/// Room left in `Poll::MAX_SIZE` for the fields that no limit bounds: ids,
+/// times, modes and the eligibility root.
+const FIXED_POLL_SIZE: u64 = 1024;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub(crate) struct PollConfig {
+    pub(crate) max_title_len: u32,
+    pub(crate) max_description_len: u32,
+    pub(crate) max_option_len: u32,
+    pub(crate) min_options: u32,
+    pub(crate) max_options: u32,
+    /// How far in the future a deadline may be set, in nanoseconds.
+    pub(crate) max_deadline_horizon: u64,
+    /// The `pps_token` canister that backs quadratic voting credits.
+    pub(crate) pps_token: Option<Principal>,
+    /// ICRC-1 ledgers that polls may be token-gated on, besides the PPS token.
+    pub(crate) allowed_ledgers: Vec<Principal>,
+}
+
+impl Default for PollConfig {
+    fn default() -> Self {
+        PollConfig {
+            max_title_len: 200,
+            max_description_len: 2_000,
+            max_option_len: 200,
+            min_options: 2,
+            max_options: 20,
+            max_deadline_horizon: 365 * NANOS_PER_DAY,
+            pps_token: None,
+            allowed_ledgers: Vec::new(),
+        }
+    }
+}
+
+/// Size of the config written before it was versioned: five bincode `u32`
+/// limits and the `u64` horizon, with no version byte.
+const UNVERSIONED_CONFIG_SIZE: usize = 28;
+
+impl Versioned for PollConfig {
+    const VERSION: u8 = 2;
+    const MAX_SIZE: u32 = 1024; // 1KB
+
+    fn decode_unversioned(bytes: &[u8]) -> Option<Result<Self, PollError>> {
+        (bytes.len() == UNVERSIONED_CONFIG_SIZE)
+            .then(|| decode_payload::<PollConfigV0>(0, bytes).map(|config| PollConfigV1::from(config).into()))
+    }
+
+    fn decode_version(version: u8, payload: &[u8]) -> Result<Self, PollError> {
+        match version {
+            1 => decode_payload::<PollConfigV1>(version, payload).map(PollConfig::from),
+            Self::VERSION => decode_payload(version, payload),
+            _ => Err(unknown_version(version)),
+        }
+    }
+}
+
+/// Unversioned layout of `PollConfig`, written before it named a token canister.
+#[derive(Deserialize)]
+struct PollConfigV0 {
+    max_title_len: u32,
+    max_description_len: u32,
+    max_option_len: u32,
+    min_options: u32,
+    max_options: u32,
+    max_deadline_horizon: u64,
+}
+
+impl From<PollConfigV0> for PollConfigV1 {
+    fn from(config: PollConfigV0) -> Self {
+        PollConfigV1 {
+            max_title_len: config.max_title_len,
+            max_description_len: config.max_description_len,
+            max_option_len: config.max_option_len,
+            min_options: config.min_options,
+            max_options: config.max_options,
+            max_deadline_horizon: config.max_deadline_horizon,
+            pps_token: None,
+        }
+    }
+}
+
+/// Version 1 layout of `PollConfig`, written before ledgers were allowlisted.
+#[derive(Deserialize)]
+struct PollConfigV1 {
+    max_title_len: u32,
+    max_description_len: u32,
+    max_option_len: u32,
+    min_options: u32,
+    max_options: u32,
+    max_deadline_horizon: u64,
+    pps_token: Option<Principal>,
+}
+
+impl From<PollConfigV1> for PollConfig {
+    fn from(config: PollConfigV1) -> Self {
+        PollConfig {
+            max_title_len: config.max_title_len,
+            max_description_len: config.max_description_len,
+            max_option_len: config.max_option_len,
+            min_options: config.min_options,
+            max_options: config.max_options,
+            max_deadline_horizon: config.max_deadline_horizon,
+            pps_token: config.pps_token,
+            allowed_ledgers: Vec::new(),
+        }
+    }
+}
+
+impl PollConfig {
+    /// Whether polls may be token-gated on `ledger`.
+    pub(crate) fn allows_ledger(&self, ledger: Principal) -> bool {
+        self.pps_token == Some(ledger) || self.allowed_ledgers.contains(&ledger)
+    }
+}
+
+impl PollConfig {
+    pub(crate) fn validate(&self) -> Result<(), PollError> {
+        let reason = if self.min_options < 2 {
+            "min_options must be at least 2"
+        } else if self.min_options > self.max_options {
+            "min_options must not exceed max_options"
+        } else if self.max_options > MAX_OPTIONS {
+            "max_options must not exceed 1000"
+        } else if self.max_title_len == 0 || self.max_option_len == 0 {
+            "max_title_len and max_option_len must be positive"
+        } else if self.largest_poll_size() > <Poll as Versioned>::MAX_SIZE as u64 {
+            "the length limits allow polls larger than 1MB"
+        } else if self.max_deadline_horizon == 0 {
+            "max_deadline_horizon must be positive"
+        } else if self.allowed_ledgers.len() > MAX_ALLOWED_LEDGERS {
+            "allowed_ledgers takes at most 16 ledgers"
+        } else {
+            return Ok(());
+        };
+
+        Err(PollError::InvalidConfig { reason: reason.to_string() })
+    }
+
+    /// Upper bound on the encoded size of a poll within these limits, counting
+    /// four bytes per character and a length prefix per string and list.
+    fn largest_poll_size(&self) -> u64 {
+        let string = |len: u32| 8 + 4 * len as u64;
+        let options = 8 + self.max_options as u64 * string(self.max_option_len);
+        // Survey questions take no more options, and conditions on as many.
+        let question = string(self.max_title_len) + options + 8 + 4 * self.max_options as u64 + 16;
+        let body = options.max(8 + MAX_QUESTIONS as u64 * question);
+        string(self.max_title_len) + string(self.max_description_len) + body + FIXED_POLL_SIZE
+    }
+
+    pub(crate) fn validate_poll(&self, args: &CreatePollArgs, now: u64) -> Result<(), PollError> {
+        if args.title.trim().is_empty() {
+            return Err(PollError::EmptyTitle);
+        }
+        if args.title.chars().count() > self.max_title_len as usize {
+            return Err(PollError::TitleTooLong { max: self.max_title_len });
+        }
+        if args.description.chars().count() > self.max_description_len as usize {
+            return Err(PollError::DescriptionTooLong { max: self.max_description_len });
+        }
+
+        let voting_mode = args.voting_mode.unwrap_or_default();
+        match &args.questions {
+            None => self.validate_options(&args.options, voting_mode, args.seats.unwrap_or(1))?,
+            Some(questions) => {
+                let per_question = args.voting_mode.is_some() || args.seats.is_some();
+                let reason = if !args.options.is_empty() || per_question {
+                    Some("surveys set options and voting modes per question".to_string())
+                } else if args.free_text.is_some() {
+                    Some("surveys do not take free-text answers".to_string())
+                } else if questions.is_empty() || questions.len() > MAX_QUESTIONS {
+                    Some(format!("surveys take from 1 to {} questions", MAX_QUESTIONS))
+                } else {
+                    None
+                };
+                if let Some(reason) = reason {
+                    return Err(PollError::InvalidVotingMode { reason });
+                }
+                for (index, question) in questions.iter().enumerate() {
+                    question.validate(self, &questions[..index]).map_err(|error| {
+                        PollError::InvalidQuestion { index: index as u32, error: Box::new(error) }
+                    })?;
+                }
+            }
+        }
+        if let Some(free_text) = args.free_text {
+            free_text.validate()?;
+            // Answers are listed by voter.
+            if args.ballot_visibility == Some(BallotVisibility::AggregateOnly) {
+                return Err(PollError::InvalidAnswer {
+                    reason: "aggregate-only polls do not take free-text answers".to_string(),
+                });
+            }
+        }
+        if args.reveal_window.is_some() {
+            secret::validate(args)?;
+        }
+        if let Some(eligibility) = &args.eligibility {
+            eligibility.validate(args)?;
+        }
+        if let Some(token_gate) = &args.token_gate {
+            token_gate.validate(args, self)?;
+        }
+        if voting_mode.is_pps_backed() {
+            // Voters' locks are checked against the deadline, and must outlast it.
+            let reason = match (self.pps_token, args.deadline) {
+                (None, _) => Some("no PPS token canister is configured"),
+                (_, None) => Some("PPS-backed polls need a deadline"),
+                _ => None,
+            };
+            if let Some(reason) = reason {
+                return Err(PollError::InvalidVotingMode { reason: reason.to_string() });
+            }
+        }
+
+        let latest = now.saturating_add(self.max_deadline_horizon);
+        if let Some(deadline) = args.deadline {
+            if deadline <= now {
+                return Err(PollError::DeadlineInPast);
+            }
+            if deadline > latest {
+                return Err(PollError::DeadlineTooFar { latest });
+            }
+        }
+        if let Some(start_time) = args.start_time {
+            if start_time > latest {
+                return Err(PollError::DeadlineTooFar { latest });
+            }
+            if args.deadline.is_some_and(|deadline| start_time >= deadline) {
+                return Err(PollError::StartAfterDeadline);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the options of a poll or survey question against the limits and
+    /// its voting mode.
+    pub(crate) fn validate_options(
+        &self,
+        options: &[String],
+        voting_mode: VotingMode,
+        seats: u32,
+    ) -> Result<(), PollError> {
+        let count = options.len();
+        if !voting_mode.takes_options() {
+            if count > 0 {
+                return Err(PollError::InvalidVotingMode {
+                    reason: "scale polls take no options".to_string(),
+                });
+            }
+        } else if count < self.min_options as usize || count > self.max_options as usize {
+            return Err(PollError::InvalidOptionCount {
+                min: self.min_options,
+                max: self.max_options,
+            });
+        }
+        for (index, option) in options.iter().enumerate() {
+            let index = index as u32;
+            if option.trim().is_empty() {
+                return Err(PollError::EmptyOption { index });
+            }
+            if option.chars().count() > self.max_option_len as usize {
+                return Err(PollError::OptionTooLong { index, max: self.max_option_len });
+            }
+            if options[..index as usize].iter().any(|other| other.trim() == option.trim()) {
+                return Err(PollError::DuplicateOption { index });
+            }
+        }
+        voting_mode.validate(count, seats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::open_poll;
+
+    #[test]
+    fn unversioned_config_still_decodes() {
+        let legacy = (200u32, 2_000u32, 200u32, 3u32, 12u32, NANOS_PER_DAY);
+        let bytes = bincode::serialize(&legacy).unwrap();
+        assert_eq!(bytes.len(), UNVERSIONED_CONFIG_SIZE);
+
+        let config = Envelope::<PollConfig>::from_bytes(Cow::Owned(bytes)).decode().unwrap();
+        assert_eq!((config.min_options, config.max_options), (3, 12));
+        assert_eq!(config.max_deadline_horizon, NANOS_PER_DAY);
+        assert_eq!(config.pps_token, None);
+        assert!(config.allowed_ledgers.is_empty());
+    }
+
+    fn poll(title: &str, options: &[&str]) -> CreatePollArgs {
+        CreatePollArgs {
+            title: title.to_string(),
+            options: options.iter().map(|option| option.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn configs_keep_their_limits_consistent() {
+        assert_eq!(PollConfig::default().validate(), Ok(()));
+        let invalid = [
+            PollConfig { min_options: 1, ..PollConfig::default() },
+            PollConfig { min_options: 5, max_options: 4, ..PollConfig::default() },
+            PollConfig { max_options: MAX_OPTIONS + 1, ..PollConfig::default() },
+            PollConfig { max_title_len: 0, ..PollConfig::default() },
+            PollConfig { max_deadline_horizon: 0, ..PollConfig::default() },
+            PollConfig {
+                allowed_ledgers: vec![Principal::anonymous(); MAX_ALLOWED_LEDGERS + 1],
+                ..PollConfig::default()
+            },
+        ];
+        for config in invalid {
+            assert!(matches!(config.validate(), Err(PollError::InvalidConfig { .. })), "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn the_largest_allowed_poll_fits_its_bounds() {
+        // The widest config the size check accepts for the longest options.
+        let mut config = PollConfig {
+            max_options: MAX_OPTIONS,
+            max_option_len: 1,
+            max_title_len: 1,
+            max_description_len: 1,
+            ..PollConfig::default()
+        };
+        assert_eq!(config.validate(), Ok(()));
+        while config.validate().is_ok() {
+            config.max_option_len += 1;
+        }
+        config.max_option_len -= 1;
+        while config.validate().is_ok() {
+            config.max_description_len += 1;
+        }
+        assert!(matches!(config.validate(), Err(PollError::InvalidConfig { .. })));
+        config.max_description_len -= 1;
+
+        // Every character takes four bytes, and every question the most options.
+        let text = |first: u32, len: u32| {
+            (first..first + len).map(|code| char::from_u32(0x10000 + code).unwrap()).collect::<String>()
+        };
+        let options: Vec<String> = (0..config.max_options)
+            .map(|index| text(index, config.max_option_len))
+            .collect();
+        let survey = (0..MAX_QUESTIONS)
+            .map(|index| survey::chained_question(text(0, 1), options.clone(), index == 0))
+            .collect();
+        let args = CreatePollArgs {
+            title: text(0, 1),
+            description: text(0, config.max_description_len),
+            questions: Some(survey),
+            ..Default::default()
+        };
+        assert_eq!(config.validate_poll(&args, 1_000), Ok(()));
+        let poll = Poll { questions: args.questions.unwrap(), ..open_poll(1, VoteChangePolicy::Immutable) };
+        let poll = Poll { title: args.title, description: args.description, options: Vec::new(), ..poll };
+        let bytes = Envelope::new(&poll).to_bytes().len();
+        assert!(bytes <= <Envelope<Poll>>::MAX_SIZE as usize, "{} bytes", bytes);
+
+        let counts = vec![u64::MAX; MAX_OPTIONS as usize];
+        let tally = Tally { counts, unique_voters: u64::MAX, finalized_at: Some(0) };
+        assert!(tally.to_bytes().len() <= Tally::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn new_polls_are_checked_against_the_limits() {
+        let config = PollConfig {
+            max_title_len: 5,
+            max_option_len: 3,
+            max_options: 3,
+            ..PollConfig::default()
+        };
+        let now = 1_000;
+        let check = |args: CreatePollArgs| config.validate_poll(&args, now);
+
+        assert_eq!(check(poll("Lunch", &["a", "b"])), Ok(()));
+        assert_eq!(check(poll("  ", &["a", "b"])), Err(PollError::EmptyTitle));
+        assert_eq!(check(poll("Dinner", &["a", "b"])), Err(PollError::TitleTooLong { max: 5 }));
+        let description = CreatePollArgs { description: "x".repeat(2_001), ..poll("Lunch", &["a", "b"]) };
+        assert_eq!(check(description), Err(PollError::DescriptionTooLong { max: 2_000 }));
+
+        let counts = PollError::InvalidOptionCount { min: 2, max: 3 };
+        assert_eq!(check(poll("Lunch", &["a"])), Err(counts.clone()));
+        assert_eq!(check(poll("Lunch", &["a", "b", "c", "d"])), Err(counts));
+        assert_eq!(check(poll("Lunch", &["a", " "])), Err(PollError::EmptyOption { index: 1 }));
+        let long = check(poll("Lunch", &["a", "soup"]));
+        assert_eq!(long, Err(PollError::OptionTooLong { index: 1, max: 3 }));
+        assert_eq!(check(poll("Lunch", &["a", "b", "a "])), Err(PollError::DuplicateOption { index: 2 }));
+
+        let latest = now + config.max_deadline_horizon;
+        let timed = |start_time, deadline| {
+            CreatePollArgs { start_time, deadline, ..poll("Lunch", &["a", "b"]) }
+        };
+        assert_eq!(check(timed(None, Some(now))), Err(PollError::DeadlineInPast));
+        assert_eq!(check(timed(None, Some(latest + 1))), Err(PollError::DeadlineTooFar { latest }));
+        assert_eq!(check(timed(Some(latest + 1), None)), Err(PollError::DeadlineTooFar { latest }));
+        assert_eq!(check(timed(Some(2_000), Some(2_000))), Err(PollError::StartAfterDeadline));
+        assert_eq!(check(timed(Some(2_000), Some(latest))), Ok(()));
+    }
+
+    #[test]
+    fn token_gates_name_an_allowed_ledger() {
+        let (pps, ledger) = (Principal::from_slice(&[50]), Principal::from_slice(&[60]));
+        let config = PollConfig {
+            pps_token: Some(pps),
+            allowed_ledgers: vec![ledger],
+            ..PollConfig::default()
+        };
+        let gated = |token| CreatePollArgs {
+            token_gate: Some(TokenGate { token, min_balance: 1 }),
+            ..poll("Lunch", &["a", "b"])
+        };
+        for token in [None, Some(pps), Some(ledger)] {
+            assert_eq!(config.validate_poll(&gated(token), 1_000), Ok(()));
+        }
+        let unlisted = config.validate_poll(&gated(Some(Principal::from_slice(&[70]))), 1_000);
+        assert!(matches!(unlisted, Err(PollError::InvalidConfig { .. })));
+        let unconfigured = PollConfig::default().validate_poll(&gated(None), 1_000);
+        assert!(matches!(unconfigured, Err(PollError::InvalidConfig { .. })));
+    }
+}
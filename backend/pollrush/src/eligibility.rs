@@ -0,0 +1,123 @@
+//! Voter eligibility by Merkle root, for allowlists too large to store.
+//!
+//! The creator commits to a list of `(principal, weight)` entries with the
+//! root of a `pollrush_common::merkle` tree over them, and each voter proves
+//! their entry with the sibling hashes on its path to the root. Unweighted
+//! lists give every voter weight 1.
+
+use super::*;
+use pollrush_common::merkle::{leaf_hash, node_hash, Hash};
+#[cfg(test)]
+use pollrush_common::merkle::MerkleTree;
+
+/// Longest accepted proof, enough for 2^32 voters.
+const MAX_PROOF_LEN: usize = 32;
+
+/// Who may vote in a poll, as set by its creator.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Eligibility {
+    merkle_root: Vec<u8>,
+    /// Ballots count with the voter's weight rather than once.
+    pub(crate) weighted: bool,
+}
+
+/// A voter's entry in the eligibility list and the sibling hashes from its
+/// leaf to the root.
+#[derive(CandidType, Deserialize, Clone)]
+pub(crate) struct EligibilityProof {
+    weight: u64,
+    path: Vec<Vec<u8>>,
+}
+
+fn invalid(reason: &str) -> PollError {
+    PollError::InvalidConfig { reason: reason.to_string() }
+}
+
+impl Eligibility {
+    /// Checks the eligibility settings of a new poll.
+    pub(crate) fn validate(&self, args: &CreatePollArgs) -> Result<(), PollError> {
+        if self.merkle_root.len() != 32 {
+            return Err(invalid("merkle_root must be a 32-byte SHA-256 hash"));
+        }
+        // Surveys and secret ballots take no proof.
+        if args.questions.is_some() || args.reveal_window.is_some() {
+            return Err(invalid("surveys and secret ballots do not support eligibility lists"));
+        }
+        // Only tallies of plain selections can be weighted.
+        let mode = args.voting_mode.unwrap_or_default();
+        let weightable = matches!(
+            mode,
+            VotingMode::SingleChoice | VotingMode::MultipleChoice { .. } | VotingMode::Scale { .. },
+        );
+        if self.weighted && !weightable {
+            return Err(invalid("only single-choice, multiple-choice and scale polls can be weighted"));
+        }
+        Ok(())
+    }
+
+    /// Checks that `voter` is on the list and returns the weight their ballot
+    /// counts with.
+    pub(crate) fn verify(
+        &self,
+        voter: Principal,
+        proof: Option<&EligibilityProof>,
+    ) -> Result<u64, PollError> {
+        let proof = proof.ok_or(PollError::NotEligible)?;
+        if proof.path.len() > MAX_PROOF_LEN || (self.weighted && proof.weight == 0) {
+            return Err(PollError::NotEligible);
+        }
+        let mut hash = leaf_hash(voter, proof.weight);
+        for sibling in &proof.path {
+            let sibling: &Hash = sibling.as_slice().try_into().map_err(|_| PollError::NotEligible)?;
+            hash = node_hash(&hash, sibling);
+        }
+        if hash.as_slice() != self.merkle_root {
+            return Err(PollError::NotEligible);
+        }
+        Ok(if self.weighted { proof.weight } else { 1 })
+    }
+}
+
+/// Eligibility settings committing to the list `tree` was built from.
+#[cfg(test)]
+pub(crate) fn eligibility(tree: &MerkleTree, weighted: bool) -> Eligibility {
+    Eligibility { merkle_root: tree.root().to_vec(), weighted }
+}
+
+/// Proof for the entry at `index` of the list `tree` was built from, which has
+/// `weight`.
+#[cfg(test)]
+pub(crate) fn proof(tree: &MerkleTree, index: usize, weight: u64) -> EligibilityProof {
+    EligibilityProof { weight, path: tree.proof(index).iter().map(|hash| hash.to_vec()).collect() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proofs_verify_only_the_listed_entry() {
+        let voters: Vec<(Principal, u64)> = (1..=5u8)
+            .map(|id| (Principal::from_slice(&[id]), id as u64))
+            .collect();
+        let tree = MerkleTree::new(&voters);
+        let weighted = eligibility(&tree, true);
+        let unweighted = Eligibility { weighted: false, ..weighted.clone() };
+
+        for (index, &(voter, weight)) in voters.iter().enumerate() {
+            let proven = proof(&tree, index, weight);
+            assert_eq!(weighted.verify(voter, Some(&proven)), Ok(weight));
+            assert_eq!(unweighted.verify(voter, Some(&proven)), Ok(1));
+        }
+
+        let (alice, bob) = (voters[0].0, voters[1].0);
+        let outsider = Principal::from_slice(&[9]);
+        assert_eq!(weighted.verify(alice, None), Err(PollError::NotEligible));
+        assert_eq!(weighted.verify(alice, Some(&proof(&tree, 0, 7))), Err(PollError::NotEligible));
+        assert_eq!(weighted.verify(bob, Some(&proof(&tree, 0, 1))), Err(PollError::NotEligible));
+        assert_eq!(weighted.verify(outsider, Some(&proof(&tree, 0, 1))), Err(PollError::NotEligible));
+
+        let single = MerkleTree::new(&voters[..1]);
+        assert_eq!(eligibility(&single, false).verify(alice, Some(&proof(&single, 0, 1))), Ok(1));
+    }
+}
@@ -1,57 +1,273 @@
 use candid::{CandidType, Deserialize, Principal};
-use ic_cdk::api::call::CallResult;
 use ic_cdk::api::time;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+use pollrush_common::stable::{
+    decode_payload, rekey, unknown_version, Envelope, LegacyPrincipalKey, PrincipalKey, Versioned,
+};
+use pollrush_common::{PollError, PollStatus};
 use serde::Serialize;
 use std::cell::RefCell;
 use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::thread::LocalKey;
 
-type Memory = VirtualMemory<DefaultMemoryImpl>;
-
-#[derive(CandidType, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
-struct PrincipalKey(Principal);
+mod answers;
+#[cfg(feature = "bench")]
+mod bench;
+mod config;
+mod eligibility;
+mod membership;
+mod migration;
+mod scheduler;
+mod secret;
+mod stv;
+mod survey;
+mod token;
+mod voting;
 
-impl Default for PrincipalKey {
-    fn default() -> Self {
-        // Use the anonymous principal as default
-        PrincipalKey(Principal::anonymous())
-    }
-}
+use answers::{Answer, AnswersPage, FreeText};
+use config::PollConfig;
+use eligibility::{Eligibility, EligibilityProof};
+use membership::{CreateInviteArgs, Invite, InviteCode, InviteInfo, MembersPage};
+use scheduler::PollEvent;
+use secret::{CommitArgs, Commitment, RevealArgs};
+use stv::ElectionReport;
+use token::TokenGate;
+use survey::{Question, SubmitSurveyArgs, SurveyResponse, SurveyResults};
+use voting::{
+    BallotVisibility, RankedResults, RankingMethod, ResultsVisibility, ScaleStats, VoteChangePolicy,
+    VotingMode,
+};
 
-impl Storable for PrincipalKey {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(bincode::serialize(self).unwrap())
-    }
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+type VoteKey = (u64, PrincipalKey);
+/// (poll id, question index) of a survey question.
+type QuestionKey = (u64, u32);
+type PrincipalPollKey = (PrincipalKey, u64);
+type PollIndex = StableBTreeMap<PrincipalPollKey, (), Memory>;
+type SortKey = (u64, u64);
+type SortIndex = StableBTreeMap<SortKey, (), Memory>;
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        bincode::deserialize(&bytes).unwrap()
-    }
-}
+/// Upper bound on the number of entries returned by one page of a listing.
+const MAX_PAGE_SIZE: u32 = 100;
 
-impl BoundedStorable for PrincipalKey {
-    const MAX_SIZE: u32 = 29; // Principal is 29 bytes
-    const IS_FIXED_SIZE: bool = true;
-}
+/// Upper bound on the index entries one `list_polls` page walks past, so a
+/// filter that matches few polls cannot scan the whole index in one call.
+const MAX_SCANNED_PER_PAGE: usize = 10 * MAX_PAGE_SIZE as usize;
 
 thread_local! {
+    // The canister's stable memory. Natively this is an in-memory buffer, which
+    // lets tests snapshot it and replay an upgrade on a fresh heap.
+    static STABLE_MEMORY: DefaultMemoryImpl = DefaultMemoryImpl::default();
+
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
+        MemoryManager::init(STABLE_MEMORY.with(|memory| memory.clone()))
     );
 
-    static POLLS: RefCell<StableBTreeMap<u64, Poll, Memory>> = RefCell::new(
+    static POLLS: RefCell<StableBTreeMap<u64, Envelope<Poll>, Memory>> = RefCell::new(
         StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)))
         )
     );
 
-    static VOTES: RefCell<StableBTreeMap<(u64, PrincipalKey), Vote, Memory>> = RefCell::new(
+    // Moved from memory 1 when principal keys took their current encoding;
+    // see `rekey_principal_maps`. So were the creator and voter indexes.
+    static VOTES: RefCell<StableBTreeMap<VoteKey, Envelope<Vote>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+        )
+    );
+
+    // Seeded with the number of existing polls: before this counter existed ids
+    // were assigned from `POLLS.len()` and polls were never removed.
+    static NEXT_POLL_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
+            POLLS.with(|polls| polls.borrow().len()),
+        ).expect("Failed to initialize the poll id counter")
+    );
+
+    static TALLIES: RefCell<StableBTreeMap<u64, Tally, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+        )
+    );
+
+    // Secondary indexes: polls created by, and polls voted in by, a principal.
+    static CREATOR_POLLS: RefCell<PollIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+        )
+    );
+
+    static VOTER_POLLS: RefCell<PollIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+        )
+    );
+
+    static POLL_SUMMARIES: RefCell<StableBTreeMap<u64, PollSummary, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+        )
+    );
+
+    // Listing order indexes, one per `PollSort`, keyed by `PollSummary::sort_key`.
+    static RECENT_POLLS: RefCell<SortIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+        )
+    );
+
+    static DEADLINE_POLLS: RefCell<SortIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+        )
+    );
+
+    static POPULAR_POLLS: RefCell<SortIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+        )
+    );
+
+    static CONFIG: RefCell<StableCell<Envelope<PollConfig>, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+            Envelope::new(&PollConfig::default()),
+        ).expect("Failed to initialize the poll config")
+    );
+
+    // Pending scheduler transitions keyed by (due time, poll id).
+    static SCHEDULE: RefCell<StableBTreeMap<(u64, u64), PollEvent, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+        )
+    );
+
+    // Legacy poll id -> poll id, so repeated imports of the same batch are no-ops.
+    static LEGACY_POLL_IDS: RefCell<StableBTreeMap<u64, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+        )
+    );
+
+    // Final tallies of closed ranked polls, which need every ballot to compute.
+    static RANKED_RESULTS: RefCell<StableBTreeMap<u64, Envelope<RankedResults>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+        )
+    );
+
+    static ELECTION_REPORTS: RefCell<StableBTreeMap<u64, Envelope<ElectionReport>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+        )
+    );
+
+    static CREDIT_TALLIES: RefCell<StableBTreeMap<u64, Envelope<CreditTally>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+        )
+    );
+
+    // Free-text answers, keyed like `VOTES`.
+    static ANSWERS: RefCell<StableBTreeMap<VoteKey, Envelope<Answer>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+        )
+    );
+
+    static SURVEY_RESPONSES: RefCell<StableBTreeMap<VoteKey, Envelope<SurveyResponse>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+        )
+    );
+
+    static SURVEY_TALLIES: RefCell<StableBTreeMap<QuestionKey, Tally, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+        )
+    );
+
+    // Secret ballot commitments, keyed like `VOTES`.
+    static COMMITMENTS: RefCell<StableBTreeMap<VoteKey, Envelope<Commitment>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+        )
+    );
+
+    // Ids of private polls, so listings can leave them out without decoding polls.
+    static PRIVATE_POLLS: RefCell<StableBTreeMap<u64, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+        )
+    );
+
+    // Allowlisted voters of private polls, keyed like `VOTES`.
+    static POLL_MEMBERS: RefCell<StableBTreeMap<VoteKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+        )
+    );
+
+    static INVITES: RefCell<StableBTreeMap<(u64, InviteCode), Envelope<Invite>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+        )
+    );
+
+    // Polls listed to everyone, by stored status, overall and per creator;
+    // `list_polls` reports their sum as its total.
+    static POLL_COUNTS: RefCell<StableBTreeMap<u8, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+        )
+    );
+
+    static CREATOR_POLL_COUNTS: RefCell<StableBTreeMap<(PrincipalKey, u8), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+        )
+    );
+
+    // Ids of polls that hide their results before closing, which keep a
+    // neutral place in the `VoteCount` order until then.
+    static SEALED_POLLS: RefCell<StableBTreeMap<u64, (), Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
         )
     );
 }
 
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct RewardInfo {
+    token_type: String,
+    reward_amount: u64,
+    reward_count: u32,
+}
+
+/// Status as observed at `now`: scheduled polls past their start time and open
+/// polls past their deadline are reported as transitioned even before the
+/// scheduler has persisted it.
+fn effective_status(
+    status: PollStatus,
+    start_time: Option<u64>,
+    deadline: Option<u64>,
+    now: u64,
+) -> PollStatus {
+    let status = match (status, start_time) {
+        (PollStatus::Scheduled, Some(start_time)) if now >= start_time => PollStatus::Open,
+        (status, _) => status,
+    };
+    match (status, deadline) {
+        (PollStatus::Open, Some(deadline)) if now > deadline => PollStatus::Closed,
+        (status, _) => status,
+    }
+}
+
 #[derive(CandidType, Deserialize, Clone, Serialize)]
 struct Poll {
     id: u64,
@@ -60,143 +276,3237 @@ struct Poll {
     options: Vec<String>,
     creator: Principal,
     created_at: u64,
+    start_time: Option<u64>,
     deadline: Option<u64>,
-    is_active: bool,
+    status: PollStatus,
+    closed_at: Option<u64>,
+    rewards: Option<RewardInfo>,
+    voting_mode: VotingMode,
+    /// Options to elect; above 1 only for single transferable vote polls.
+    seats: u32,
+    /// Set when ballots may carry a free-text answer.
+    free_text: Option<FreeText>,
+    /// The questions of a survey, answered with `submit_survey`; empty for
+    /// single-question polls.
+    questions: Vec<Question>,
+    vote_change: VoteChangePolicy,
+    /// Set for secret ballots: how long after closing voters may reveal the
+    /// ballots they committed to, in nanoseconds.
+    reveal_window: Option<u64>,
+    ballot_visibility: BallotVisibility,
+    results_visibility: ResultsVisibility,
+    /// Visible to, and open for voting by, only the creator and the poll's
+    /// members.
+    private: bool,
+    /// Set when voters must prove they are on a Merkle-committed list.
+    eligibility: Option<Eligibility>,
+    /// Set when voters must hold a minimum balance of a token.
+    token_gate: Option<TokenGate>,
 }
 
-impl Storable for Poll {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(bincode::serialize(self).unwrap())
+impl Poll {
+    fn status_at(&self, now: u64) -> PollStatus {
+        effective_status(self.status, self.start_time, self.deadline, now)
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        bincode::deserialize(&bytes).unwrap()
+    fn closed_at_or_deadline(&self, now: u64) -> Option<u64> {
+        match self.status_at(now) {
+            PollStatus::Closed => self.closed_at.or(self.deadline),
+            _ => self.closed_at,
+        }
     }
-}
 
-impl BoundedStorable for Poll {
-    const MAX_SIZE: u32 = 1024 * 1024; // 1MB
-    const IS_FIXED_SIZE: bool = false;
-}
+    /// Returns a copy with the lazily derived status applied, as served by queries.
+    fn observed_at(mut self, now: u64) -> Self {
+        self.closed_at = self.closed_at_or_deadline(now);
+        self.status = self.status_at(now);
+        self
+    }
 
-#[derive(CandidType, Deserialize, Serialize)]
-struct Vote {
-    poll_id: u64,
-    voter: Principal,
-    option_index: u32,
-    voted_at: u64,
+    fn is_visible_to(&self, caller: Principal) -> bool {
+        if self.creator == caller {
+            return true;
+        }
+        self.status != PollStatus::Draft && (!self.private || membership::is_member(self.id, caller))
+    }
 }
 
-impl Storable for Vote {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(bincode::serialize(self).unwrap())
+impl Versioned for Poll {
+    const VERSION: u8 = 12;
+    // 1MB with the version byte, the bound unversioned polls were stored under.
+    const MAX_SIZE: u32 = 1024 * 1024 - 1;
+
+    fn decode_unversioned(bytes: &[u8]) -> Option<Result<Self, PollError>> {
+        (unversioned_poll_len(bytes) == Some(bytes.len())).then(|| {
+            decode_payload::<PollV0>(0, bytes).map(|poll| {
+                let poll = PollV4::from(PollV3::from(PollV2::from(PollV1::from(poll))));
+                let poll = PollV8::from(PollV7::from(PollV6::from(PollV5::from(poll))));
+                PollV11::from(PollV10::from(PollV9::from(poll))).into()
+            })
+        })
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        bincode::deserialize(&bytes).unwrap()
+    fn decode_version(version: u8, payload: &[u8]) -> Result<Self, PollError> {
+        match version {
+            1 => decode_payload::<PollV1>(version, payload).map(|poll| {
+                let poll = PollV4::from(PollV3::from(PollV2::from(poll)));
+                let poll = PollV8::from(PollV7::from(PollV6::from(PollV5::from(poll))));
+                PollV11::from(PollV10::from(PollV9::from(poll))).into()
+            }),
+            2 => decode_payload::<PollV2>(version, payload).map(|poll| {
+                let poll = PollV6::from(PollV5::from(PollV4::from(PollV3::from(poll))));
+                let poll = PollV9::from(PollV8::from(PollV7::from(poll)));
+                PollV11::from(PollV10::from(poll)).into()
+            }),
+            3 => decode_payload::<PollV3>(version, payload).map(|poll| {
+                let poll = PollV6::from(PollV5::from(PollV4::from(poll)));
+                let poll = PollV9::from(PollV8::from(PollV7::from(poll)));
+                PollV11::from(PollV10::from(poll)).into()
+            }),
+            4 => decode_payload::<PollV4>(version, payload).map(|poll| {
+                let poll = PollV7::from(PollV6::from(PollV5::from(poll)));
+                PollV11::from(PollV10::from(PollV9::from(PollV8::from(poll)))).into()
+            }),
+            5 => decode_payload::<PollV5>(version, payload).map(|poll| {
+                let poll = PollV8::from(PollV7::from(PollV6::from(poll)));
+                PollV11::from(PollV10::from(PollV9::from(poll))).into()
+            }),
+            6 => decode_payload::<PollV6>(version, payload).map(|poll| {
+                PollV11::from(PollV10::from(PollV9::from(PollV8::from(PollV7::from(poll))))).into()
+            }),
+            7 => decode_payload::<PollV7>(version, payload)
+                .map(|poll| PollV11::from(PollV10::from(PollV9::from(PollV8::from(poll)))).into()),
+            8 => decode_payload::<PollV8>(version, payload)
+                .map(|poll| PollV11::from(PollV10::from(PollV9::from(poll))).into()),
+            9 => decode_payload::<PollV9>(version, payload)
+                .map(|poll| PollV11::from(PollV10::from(poll)).into()),
+            10 => decode_payload::<PollV10>(version, payload).map(|poll| PollV11::from(poll).into()),
+            11 => decode_payload::<PollV11>(version, payload).map(Poll::from),
+            Self::VERSION => decode_payload(version, payload),
+            _ => Err(unknown_version(version)),
+        }
     }
 }
 
-impl BoundedStorable for Vote {
-    const MAX_SIZE: u32 = 1024; // 1KB
-    const IS_FIXED_SIZE: bool = false;
+/// Length `bytes` would have as an unversioned poll, walking the length
+/// prefixes of its strings and lists, or `None` when they do not fit. The
+/// version byte of an enveloped poll shifts every prefix one byte along, so
+/// its walk does not come out at its length.
+fn unversioned_poll_len(bytes: &[u8]) -> Option<usize> {
+    let u64_at = |at: usize| Some(u64::from_le_bytes(bytes.get(at..at.checked_add(8)?)?.try_into().ok()?));
+    let skip_bytes = |at: usize| at.checked_add(8)?.checked_add(usize::try_from(u64_at(at)?).ok()?);
+
+    // The id, then the title and description.
+    let mut at = skip_bytes(skip_bytes(8)?)?;
+    let options = u64_at(at)?;
+    at = at.checked_add(8)?;
+    for _ in 0..options {
+        at = skip_bytes(at)?;
+        if at > bytes.len() {
+            return None;
+        }
+    }
+    // The creator, the creation time and the deadline.
+    at = skip_bytes(at)?.checked_add(8)?;
+    at += match bytes.get(at)? {
+        0 => 1,
+        1 => 9,
+        _ => return None,
+    };
+    // Whether the poll is active.
+    matches!(bytes.get(at)?, 0 | 1).then_some(at + 1)
 }
 
-#[derive(CandidType, Deserialize)]
-struct CreatePollArgs {
+/// Unversioned layout of `Poll`, written before records carried a version byte.
+#[derive(Deserialize)]
+struct PollV0 {
+    id: u64,
     title: String,
     description: String,
     options: Vec<String>,
+    creator: Principal,
+    created_at: u64,
     deadline: Option<u64>,
+    is_active: bool,
 }
 
-#[derive(CandidType, Deserialize)]
-struct VoteArgs {
-    poll_id: u64,
-    option_index: u32,
+impl From<PollV0> for PollV1 {
+    fn from(poll: PollV0) -> Self {
+        PollV1 {
+            id: poll.id,
+            title: poll.title,
+            description: poll.description,
+            options: poll.options,
+            creator: poll.creator,
+            created_at: poll.created_at,
+            start_time: None,
+            deadline: poll.deadline,
+            // When an inactive poll was closed was not recorded.
+            status: if poll.is_active { PollStatus::Open } else { PollStatus::Closed },
+            closed_at: None,
+            rewards: None,
+        }
+    }
 }
 
-#[ic_cdk::query]
-fn get_poll(id: u64) -> Option<Poll> {
-    POLLS.with(|polls| polls.borrow().get(&id))
+/// Version 1 layout of `Poll`, written before polls had a voting mode.
+#[derive(Deserialize)]
+struct PollV1 {
+    id: u64,
+    title: String,
+    description: String,
+    options: Vec<String>,
+    creator: Principal,
+    created_at: u64,
+    start_time: Option<u64>,
+    deadline: Option<u64>,
+    status: PollStatus,
+    closed_at: Option<u64>,
+    rewards: Option<RewardInfo>,
 }
 
-#[ic_cdk::query]
-fn get_polls() -> Vec<Poll> {
-    POLLS.with(|polls| polls.borrow().iter().map(|(_, poll)| poll).collect())
+impl From<PollV1> for PollV2 {
+    fn from(poll: PollV1) -> Self {
+        PollV2 {
+            id: poll.id,
+            title: poll.title,
+            description: poll.description,
+            options: poll.options,
+            creator: poll.creator,
+            created_at: poll.created_at,
+            start_time: poll.start_time,
+            deadline: poll.deadline,
+            status: poll.status,
+            closed_at: poll.closed_at,
+            rewards: poll.rewards,
+            voting_mode: VotingMode::SingleChoice,
+        }
+    }
 }
 
-#[ic_cdk::update]
-fn create_poll(args: CreatePollArgs) -> Result<u64, String> {
-    let caller = ic_cdk::caller();
-    let id = POLLS.with(|polls| polls.borrow().len() as u64);
-    
-    let poll = Poll {
-        id,
-        title: args.title,
-        description: args.description,
-        options: args.options,
-        creator: caller,
-        created_at: time(),
-        deadline: args.deadline,
-        is_active: true,
-    };
+/// Version 2 layout of `Poll`, written before polls had seats.
+#[derive(Deserialize)]
+struct PollV2 {
+    id: u64,
+    title: String,
+    description: String,
+    options: Vec<String>,
+    creator: Principal,
+    created_at: u64,
+    start_time: Option<u64>,
+    deadline: Option<u64>,
+    status: PollStatus,
+    closed_at: Option<u64>,
+    rewards: Option<RewardInfo>,
+    voting_mode: VotingMode,
+}
 
-    POLLS.with(|polls| {
-        polls.borrow_mut().insert(id, poll);
-    });
+impl From<PollV2> for PollV3 {
+    fn from(poll: PollV2) -> Self {
+        PollV3 {
+            id: poll.id,
+            title: poll.title,
+            description: poll.description,
+            options: poll.options,
+            creator: poll.creator,
+            created_at: poll.created_at,
+            start_time: poll.start_time,
+            deadline: poll.deadline,
+            status: poll.status,
+            closed_at: poll.closed_at,
+            rewards: poll.rewards,
+            voting_mode: poll.voting_mode,
+            seats: 1,
+        }
+    }
+}
 
-    Ok(id)
+/// Version 3 layout of `Poll`, written before free-text answers.
+#[derive(Deserialize)]
+struct PollV3 {
+    id: u64,
+    title: String,
+    description: String,
+    options: Vec<String>,
+    creator: Principal,
+    created_at: u64,
+    start_time: Option<u64>,
+    deadline: Option<u64>,
+    status: PollStatus,
+    closed_at: Option<u64>,
+    rewards: Option<RewardInfo>,
+    voting_mode: VotingMode,
+    seats: u32,
 }
 
-#[ic_cdk::update]
-fn vote(args: VoteArgs) -> Result<(), String> {
-    let caller = ic_cdk::caller();
-    
-    POLLS.with(|polls| {
-        let poll = polls.borrow().get(&args.poll_id)
-            .ok_or("Poll not found")?;
-            
-        if !poll.is_active {
-            return Err("Poll is not active".to_string());
-        }
-        
-        if let Some(deadline) = poll.deadline {
-            if time() > deadline {
-                return Err("Poll deadline has passed".to_string());
-            }
+impl From<PollV3> for PollV4 {
+    fn from(poll: PollV3) -> Self {
+        PollV4 {
+            id: poll.id,
+            title: poll.title,
+            description: poll.description,
+            options: poll.options,
+            creator: poll.creator,
+            created_at: poll.created_at,
+            start_time: poll.start_time,
+            deadline: poll.deadline,
+            status: poll.status,
+            closed_at: poll.closed_at,
+            rewards: poll.rewards,
+            voting_mode: poll.voting_mode,
+            seats: poll.seats,
+            free_text: None,
         }
-        
-        if args.option_index as usize >= poll.options.len() {
-            return Err("Invalid option index".to_string());
+    }
+}
+
+/// Version 4 layout of `Poll`, written before surveys.
+#[derive(Deserialize)]
+struct PollV4 {
+    id: u64,
+    title: String,
+    description: String,
+    options: Vec<String>,
+    creator: Principal,
+    created_at: u64,
+    start_time: Option<u64>,
+    deadline: Option<u64>,
+    status: PollStatus,
+    closed_at: Option<u64>,
+    rewards: Option<RewardInfo>,
+    voting_mode: VotingMode,
+    seats: u32,
+    free_text: Option<FreeText>,
+}
+
+impl From<PollV4> for PollV5 {
+    fn from(poll: PollV4) -> Self {
+        PollV5 {
+            id: poll.id,
+            title: poll.title,
+            description: poll.description,
+            options: poll.options,
+            creator: poll.creator,
+            created_at: poll.created_at,
+            start_time: poll.start_time,
+            deadline: poll.deadline,
+            status: poll.status,
+            closed_at: poll.closed_at,
+            rewards: poll.rewards,
+            voting_mode: poll.voting_mode,
+            seats: poll.seats,
+            free_text: poll.free_text,
+            questions: Vec::new(),
         }
-        
-        Ok(())
-    })?;
+    }
+}
 
-    let vote = Vote {
-        poll_id: args.poll_id,
-        voter: caller,
-        option_index: args.option_index,
-        voted_at: time(),
-    };
+/// Version 5 layout of `Poll`, written before vote change policies; its
+/// ballots could always be replaced.
+#[derive(Deserialize)]
+struct PollV5 {
+    id: u64,
+    title: String,
+    description: String,
+    options: Vec<String>,
+    creator: Principal,
+    created_at: u64,
+    start_time: Option<u64>,
+    deadline: Option<u64>,
+    status: PollStatus,
+    closed_at: Option<u64>,
+    rewards: Option<RewardInfo>,
+    voting_mode: VotingMode,
+    seats: u32,
+    free_text: Option<FreeText>,
+    questions: Vec<Question>,
+}
 
-    VOTES.with(|votes| {
-        votes.borrow_mut().insert((args.poll_id, PrincipalKey(caller)), vote);
-    });
+impl From<PollV5> for PollV6 {
+    fn from(poll: PollV5) -> Self {
+        PollV6 {
+            id: poll.id,
+            title: poll.title,
+            description: poll.description,
+            options: poll.options,
+            creator: poll.creator,
+            created_at: poll.created_at,
+            start_time: poll.start_time,
+            deadline: poll.deadline,
+            status: poll.status,
+            closed_at: poll.closed_at,
+            rewards: poll.rewards,
+            voting_mode: poll.voting_mode,
+            seats: poll.seats,
+            free_text: poll.free_text,
+            questions: poll.questions,
+            vote_change: VoteChangePolicy::Changeable,
+        }
+    }
+}
 
-    Ok(())
+/// Version 6 layout of `Poll`, written before secret ballots.
+#[derive(Deserialize)]
+struct PollV6 {
+    id: u64,
+    title: String,
+    description: String,
+    options: Vec<String>,
+    creator: Principal,
+    created_at: u64,
+    start_time: Option<u64>,
+    deadline: Option<u64>,
+    status: PollStatus,
+    closed_at: Option<u64>,
+    rewards: Option<RewardInfo>,
+    voting_mode: VotingMode,
+    seats: u32,
+    free_text: Option<FreeText>,
+    questions: Vec<Question>,
+    vote_change: VoteChangePolicy,
 }
 
-#[ic_cdk::query]
-fn get_votes(poll_id: u64) -> Vec<Vote> {
-    VOTES.with(|votes| {
-        votes.borrow()
-            .iter()
-            .filter(|((id, _), _)| *id == poll_id)
-            .map(|(_, vote)| vote)
-            .collect()
-    })
+impl From<PollV6> for PollV7 {
+    fn from(poll: PollV6) -> Self {
+        PollV7 {
+            id: poll.id,
+            title: poll.title,
+            description: poll.description,
+            options: poll.options,
+            creator: poll.creator,
+            created_at: poll.created_at,
+            start_time: poll.start_time,
+            deadline: poll.deadline,
+            status: poll.status,
+            closed_at: poll.closed_at,
+            rewards: poll.rewards,
+            voting_mode: poll.voting_mode,
+            seats: poll.seats,
+            free_text: poll.free_text,
+            questions: poll.questions,
+            vote_change: poll.vote_change,
+            reveal_window: None,
+        }
+    }
+}
+
+/// Version 7 layout of `Poll`, written before ballot visibility settings; its
+/// ballots were public.
+#[derive(Deserialize)]
+struct PollV7 {
+    id: u64,
+    title: String,
+    description: String,
+    options: Vec<String>,
+    creator: Principal,
+    created_at: u64,
+    start_time: Option<u64>,
+    deadline: Option<u64>,
+    status: PollStatus,
+    closed_at: Option<u64>,
+    rewards: Option<RewardInfo>,
+    voting_mode: VotingMode,
+    seats: u32,
+    free_text: Option<FreeText>,
+    questions: Vec<Question>,
+    vote_change: VoteChangePolicy,
+    reveal_window: Option<u64>,
+}
+
+impl From<PollV7> for PollV8 {
+    fn from(poll: PollV7) -> Self {
+        PollV8 {
+            id: poll.id,
+            title: poll.title,
+            description: poll.description,
+            options: poll.options,
+            creator: poll.creator,
+            created_at: poll.created_at,
+            start_time: poll.start_time,
+            deadline: poll.deadline,
+            status: poll.status,
+            closed_at: poll.closed_at,
+            rewards: poll.rewards,
+            voting_mode: poll.voting_mode,
+            seats: poll.seats,
+            free_text: poll.free_text,
+            questions: poll.questions,
+            vote_change: poll.vote_change,
+            reveal_window: poll.reveal_window,
+            ballot_visibility: BallotVisibility::Public,
+        }
+    }
+}
+
+/// Version 8 layout of `Poll`, written before results visibility settings;
+/// its results were always shown.
+#[derive(Deserialize)]
+struct PollV8 {
+    id: u64,
+    title: String,
+    description: String,
+    options: Vec<String>,
+    creator: Principal,
+    created_at: u64,
+    start_time: Option<u64>,
+    deadline: Option<u64>,
+    status: PollStatus,
+    closed_at: Option<u64>,
+    rewards: Option<RewardInfo>,
+    voting_mode: VotingMode,
+    seats: u32,
+    free_text: Option<FreeText>,
+    questions: Vec<Question>,
+    vote_change: VoteChangePolicy,
+    reveal_window: Option<u64>,
+    ballot_visibility: BallotVisibility,
+}
+
+impl From<PollV8> for PollV9 {
+    fn from(poll: PollV8) -> Self {
+        PollV9 {
+            id: poll.id,
+            title: poll.title,
+            description: poll.description,
+            options: poll.options,
+            creator: poll.creator,
+            created_at: poll.created_at,
+            start_time: poll.start_time,
+            deadline: poll.deadline,
+            status: poll.status,
+            closed_at: poll.closed_at,
+            rewards: poll.rewards,
+            voting_mode: poll.voting_mode,
+            seats: poll.seats,
+            free_text: poll.free_text,
+            questions: poll.questions,
+            vote_change: poll.vote_change,
+            reveal_window: poll.reveal_window,
+            ballot_visibility: poll.ballot_visibility,
+            results_visibility: ResultsVisibility::Always,
+        }
+    }
+}
+
+/// Version 9 layout of `Poll`, written before private polls.
+#[derive(Deserialize)]
+struct PollV9 {
+    id: u64,
+    title: String,
+    description: String,
+    options: Vec<String>,
+    creator: Principal,
+    created_at: u64,
+    start_time: Option<u64>,
+    deadline: Option<u64>,
+    status: PollStatus,
+    closed_at: Option<u64>,
+    rewards: Option<RewardInfo>,
+    voting_mode: VotingMode,
+    seats: u32,
+    free_text: Option<FreeText>,
+    questions: Vec<Question>,
+    vote_change: VoteChangePolicy,
+    reveal_window: Option<u64>,
+    ballot_visibility: BallotVisibility,
+    results_visibility: ResultsVisibility,
+}
+
+impl From<PollV9> for PollV10 {
+    fn from(poll: PollV9) -> Self {
+        PollV10 {
+            id: poll.id,
+            title: poll.title,
+            description: poll.description,
+            options: poll.options,
+            creator: poll.creator,
+            created_at: poll.created_at,
+            start_time: poll.start_time,
+            deadline: poll.deadline,
+            status: poll.status,
+            closed_at: poll.closed_at,
+            rewards: poll.rewards,
+            voting_mode: poll.voting_mode,
+            seats: poll.seats,
+            free_text: poll.free_text,
+            questions: poll.questions,
+            vote_change: poll.vote_change,
+            reveal_window: poll.reveal_window,
+            ballot_visibility: poll.ballot_visibility,
+            results_visibility: poll.results_visibility,
+            private: false,
+        }
+    }
+}
+
+/// Version 10 layout of `Poll`, written before eligibility lists.
+#[derive(Deserialize)]
+struct PollV10 {
+    id: u64,
+    title: String,
+    description: String,
+    options: Vec<String>,
+    creator: Principal,
+    created_at: u64,
+    start_time: Option<u64>,
+    deadline: Option<u64>,
+    status: PollStatus,
+    closed_at: Option<u64>,
+    rewards: Option<RewardInfo>,
+    voting_mode: VotingMode,
+    seats: u32,
+    free_text: Option<FreeText>,
+    questions: Vec<Question>,
+    vote_change: VoteChangePolicy,
+    reveal_window: Option<u64>,
+    ballot_visibility: BallotVisibility,
+    results_visibility: ResultsVisibility,
+    private: bool,
+}
+
+impl From<PollV10> for PollV11 {
+    fn from(poll: PollV10) -> Self {
+        PollV11 {
+            id: poll.id,
+            title: poll.title,
+            description: poll.description,
+            options: poll.options,
+            creator: poll.creator,
+            created_at: poll.created_at,
+            start_time: poll.start_time,
+            deadline: poll.deadline,
+            status: poll.status,
+            closed_at: poll.closed_at,
+            rewards: poll.rewards,
+            voting_mode: poll.voting_mode,
+            seats: poll.seats,
+            free_text: poll.free_text,
+            questions: poll.questions,
+            vote_change: poll.vote_change,
+            reveal_window: poll.reveal_window,
+            ballot_visibility: poll.ballot_visibility,
+            results_visibility: poll.results_visibility,
+            private: poll.private,
+            eligibility: None,
+        }
+    }
+}
+
+/// Version 11 layout of `Poll`, written before token-gated voting.
+#[derive(Deserialize)]
+struct PollV11 {
+    id: u64,
+    title: String,
+    description: String,
+    options: Vec<String>,
+    creator: Principal,
+    created_at: u64,
+    start_time: Option<u64>,
+    deadline: Option<u64>,
+    status: PollStatus,
+    closed_at: Option<u64>,
+    rewards: Option<RewardInfo>,
+    voting_mode: VotingMode,
+    seats: u32,
+    free_text: Option<FreeText>,
+    questions: Vec<Question>,
+    vote_change: VoteChangePolicy,
+    reveal_window: Option<u64>,
+    ballot_visibility: BallotVisibility,
+    results_visibility: ResultsVisibility,
+    private: bool,
+    eligibility: Option<Eligibility>,
+}
+
+impl From<PollV11> for Poll {
+    fn from(poll: PollV11) -> Self {
+        Poll {
+            id: poll.id,
+            title: poll.title,
+            description: poll.description,
+            options: poll.options,
+            creator: poll.creator,
+            created_at: poll.created_at,
+            start_time: poll.start_time,
+            deadline: poll.deadline,
+            status: poll.status,
+            closed_at: poll.closed_at,
+            rewards: poll.rewards,
+            voting_mode: poll.voting_mode,
+            seats: poll.seats,
+            free_text: poll.free_text,
+            questions: poll.questions,
+            vote_change: poll.vote_change,
+            reveal_window: poll.reveal_window,
+            ballot_visibility: poll.ballot_visibility,
+            results_visibility: poll.results_visibility,
+            private: poll.private,
+            eligibility: poll.eligibility,
+            token_gate: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize)]
+struct Vote {
+    poll_id: u64,
+    voter: Principal,
+    /// Selected option indices, in the order the voter gave them; for ranked
+    /// polls the ranking, most preferred first.
+    choices: Vec<u32>,
+    /// Votes given to each of `choices` in quadratic polls, empty otherwise.
+    votes: Vec<u32>,
+    /// The answer to a scale poll, which has no choices.
+    value: Option<i64>,
+    voted_at: u64,
+    /// Times the ballot has been changed since it was first cast.
+    revision: u32,
+    /// How many times the ballot counts; above 1 only in polls with a
+    /// weighted eligibility list.
+    weight: u64,
+}
+
+/// Size of an unversioned vote less its voter's bytes: the bincode `u64`
+/// poll id, voter length and cast time, and the `u32` option.
+const UNVERSIONED_VOTE_SIZE: usize = 28;
+
+impl Versioned for Vote {
+    const VERSION: u8 = 6;
+    const MAX_SIZE: u32 = 1023; // 1KB with the version byte, like unversioned votes
+
+    fn decode_unversioned(bytes: &[u8]) -> Option<Result<Self, PollError>> {
+        // The voter's length follows the poll id; in an envelope it is shifted
+        // one byte along, which makes it read as a multiple of 256.
+        let voter_len = u64::from_le_bytes(bytes.get(8..16)?.try_into().ok()?);
+        (voter_len <= 29 && bytes.len() as u64 == UNVERSIONED_VOTE_SIZE as u64 + voter_len).then(|| {
+            decode_payload::<VoteV1>(0, bytes)
+                .map(|vote| VoteV5::from(VoteV4::from(VoteV3::from(VoteV2::from(vote)))).into())
+        })
+    }
+
+    fn decode_version(version: u8, payload: &[u8]) -> Result<Self, PollError> {
+        match version {
+            1 => decode_payload::<VoteV1>(version, payload)
+                .map(|vote| VoteV5::from(VoteV4::from(VoteV3::from(VoteV2::from(vote)))).into()),
+            2 => decode_payload::<VoteV2>(version, payload)
+                .map(|vote| VoteV5::from(VoteV4::from(VoteV3::from(vote))).into()),
+            3 => decode_payload::<VoteV3>(version, payload)
+                .map(|vote| VoteV5::from(VoteV4::from(vote)).into()),
+            4 => decode_payload::<VoteV4>(version, payload).map(|vote| VoteV5::from(vote).into()),
+            5 => decode_payload::<VoteV5>(version, payload).map(Vote::from),
+            Self::VERSION => decode_payload(version, payload),
+            _ => Err(unknown_version(version)),
+        }
+    }
+}
+
+/// Version 1 layout of `Vote`, which held a single choice; also the layout of
+/// unversioned votes.
+#[derive(Deserialize)]
+struct VoteV1 {
+    poll_id: u64,
+    voter: Principal,
+    option_index: u32,
+    voted_at: u64,
+}
+
+impl From<VoteV1> for VoteV2 {
+    fn from(vote: VoteV1) -> Self {
+        VoteV2 {
+            poll_id: vote.poll_id,
+            voter: vote.voter,
+            choices: vec![vote.option_index],
+            voted_at: vote.voted_at,
+        }
+    }
+}
+
+/// Version 2 layout of `Vote`, written before quadratic polls.
+#[derive(Deserialize)]
+struct VoteV2 {
+    poll_id: u64,
+    voter: Principal,
+    choices: Vec<u32>,
+    voted_at: u64,
+}
+
+impl From<VoteV2> for VoteV3 {
+    fn from(vote: VoteV2) -> Self {
+        VoteV3 {
+            poll_id: vote.poll_id,
+            voter: vote.voter,
+            choices: vote.choices,
+            votes: Vec::new(),
+            voted_at: vote.voted_at,
+        }
+    }
+}
+
+/// Version 3 layout of `Vote`, written before scale polls.
+#[derive(Deserialize)]
+struct VoteV3 {
+    poll_id: u64,
+    voter: Principal,
+    choices: Vec<u32>,
+    votes: Vec<u32>,
+    voted_at: u64,
+}
+
+impl From<VoteV3> for VoteV4 {
+    fn from(vote: VoteV3) -> Self {
+        VoteV4 {
+            poll_id: vote.poll_id,
+            voter: vote.voter,
+            choices: vote.choices,
+            votes: vote.votes,
+            value: None,
+            voted_at: vote.voted_at,
+        }
+    }
+}
+
+/// Version 4 layout of `Vote`, written before ballots were revised.
+#[derive(Deserialize)]
+struct VoteV4 {
+    poll_id: u64,
+    voter: Principal,
+    choices: Vec<u32>,
+    votes: Vec<u32>,
+    value: Option<i64>,
+    voted_at: u64,
+}
+
+impl From<VoteV4> for VoteV5 {
+    fn from(vote: VoteV4) -> Self {
+        VoteV5 {
+            poll_id: vote.poll_id,
+            voter: vote.voter,
+            choices: vote.choices,
+            votes: vote.votes,
+            value: vote.value,
+            voted_at: vote.voted_at,
+            revision: 0,
+        }
+    }
+}
+
+/// Version 5 layout of `Vote`, written before weighted ballots.
+#[derive(Deserialize)]
+struct VoteV5 {
+    poll_id: u64,
+    voter: Principal,
+    choices: Vec<u32>,
+    votes: Vec<u32>,
+    value: Option<i64>,
+    voted_at: u64,
+    revision: u32,
+}
+
+impl From<VoteV5> for Vote {
+    fn from(vote: VoteV5) -> Self {
+        Vote {
+            poll_id: vote.poll_id,
+            voter: vote.voter,
+            choices: vote.choices,
+            votes: vote.votes,
+            value: vote.value,
+            voted_at: vote.voted_at,
+            revision: vote.revision,
+            weight: 1,
+        }
+    }
+}
+
+/// Running per-option counters for a poll, updated in the same call that writes
+/// the vote so results never need a scan over `VOTES`.
+#[derive(Deserialize, Serialize, Clone, Default)]
+struct Tally {
+    counts: Vec<u64>,
+    unique_voters: u64,
+    /// Set once the poll has closed; the counts are final from then on.
+    finalized_at: Option<u64>,
+}
+
+impl Tally {
+    /// Replaces the `previous` contribution of a voter, if any, with `counted`,
+    /// both as (option, amount) pairs.
+    fn record(&mut self, options: usize, previous: Option<&[(u32, u64)]>, counted: &[(u32, u64)]) {
+        if previous.is_none() {
+            self.unique_voters += 1;
+        }
+        replace_counts(&mut self.counts, options, previous.unwrap_or_default(), counted);
+    }
+
+    /// Removes the `previous` contribution of a voter who no longer counts.
+    fn withdraw(&mut self, previous: &[(u32, u64)]) {
+        self.unique_voters = self.unique_voters.saturating_sub(1);
+        replace_counts(&mut self.counts, 0, previous, &[]);
+    }
+}
+
+fn replace_counts(counts: &mut Vec<u64>, options: usize, previous: &[(u32, u64)], counted: &[(u32, u64)]) {
+    counts.resize(counts.len().max(options), 0);
+    for &(option, amount) in previous {
+        let count = &mut counts[option as usize];
+        *count = count.saturating_sub(amount);
+    }
+    for &(option, amount) in counted {
+        counts[option as usize] += amount;
+    }
+}
+
+/// Voice credits spent on each option of a quadratic poll, kept next to the
+/// effective votes in `Tally`.
+#[derive(Deserialize, Serialize, Default)]
+struct CreditTally {
+    credits: Vec<u64>,
+}
+
+impl Versioned for CreditTally {
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 8 * 1024; // 8KB, like `Tally`
+}
+
+impl CreditTally {
+    fn record(&mut self, options: usize, previous: Option<&Vote>, vote: &Vote) {
+        let previous = previous.map(credits_spent).unwrap_or_default();
+        replace_counts(&mut self.credits, options, &previous, &credits_spent(vote));
+    }
+
+    fn withdraw(&mut self, previous: &Vote) {
+        replace_counts(&mut self.credits, 0, &credits_spent(previous), &[]);
+    }
+}
+
+/// Voice credits a quadratic ballot spends on each of its choices.
+fn credits_spent(vote: &Vote) -> Vec<(u32, u64)> {
+    vote.choices.iter()
+        .zip(&vote.votes)
+        .map(|(&choice, &votes)| (choice, voting::quadratic_cost(&[votes])))
+        .collect()
+}
+
+impl Storable for Tally {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(bincode::serialize(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        bincode::deserialize(&bytes).unwrap()
+    }
+}
+
+impl BoundedStorable for Tally {
+    const MAX_SIZE: u32 = 8 * 1024; // 8KB, room for ~1000 options
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Compact listing metadata kept next to each poll so listings can filter,
+/// sort and count without decoding full `Poll` records.
+#[derive(Deserialize, Serialize, Clone)]
+struct PollSummary {
+    creator: Principal,
+    created_at: u64,
+    start_time: Option<u64>,
+    deadline: Option<u64>,
+    status: PollStatus,
+    votes: u64,
+}
+
+impl PollSummary {
+    fn new(poll: &Poll, votes: u64) -> Self {
+        PollSummary {
+            creator: poll.creator,
+            created_at: poll.created_at,
+            start_time: poll.start_time,
+            deadline: poll.deadline,
+            status: poll.status,
+            votes,
+        }
+    }
+
+    /// Position of the poll in the `sort` index; ascending key order is the
+    /// listing order (newest, soonest deadline, most votes first). Polls that
+    /// hide their results rank as if they had no votes until they close.
+    fn sort_key(&self, id: u64, sort: PollSort) -> SortKey {
+        match sort {
+            PollSort::CreatedAt => (u64::MAX - self.created_at, id),
+            PollSort::Deadline => (self.deadline.unwrap_or(u64::MAX), id),
+            PollSort::VoteCount if self.is_sealed(id) => (u64::MAX, id),
+            PollSort::VoteCount => (u64::MAX - self.votes, id),
+        }
+    }
+
+    fn is_sealed(&self, id: u64) -> bool {
+        !matches!(self.status, PollStatus::Closed | PollStatus::Archived)
+            && SEALED_POLLS.with(|polls| polls.borrow().contains_key(&id))
+    }
+
+    fn matches(&self, id: u64, args: &ListPollsArgs, caller: Principal, now: u64) -> bool {
+        let status = effective_status(self.status, self.start_time, self.deadline, now);
+        let status_matches = match args.status {
+            Some(wanted) => status == wanted,
+            None => status != PollStatus::Archived,
+        };
+        let private = PRIVATE_POLLS.with(|polls| polls.borrow().contains_key(&id));
+        let visible = self.creator == caller
+            || (status != PollStatus::Draft && (!private || membership::is_member(id, caller)));
+        let creator_matches = args.creator.is_none_or(|creator| creator == self.creator);
+
+        status_matches && visible && creator_matches
+    }
+}
+
+impl Storable for PollSummary {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(bincode::serialize(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        bincode::deserialize(&bytes).unwrap()
+    }
+}
+
+impl BoundedStorable for PollSummary {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum PollSort {
+    CreatedAt,
+    Deadline,
+    VoteCount,
+}
+
+impl PollSort {
+    const ALL: [PollSort; 3] = [PollSort::CreatedAt, PollSort::Deadline, PollSort::VoteCount];
+
+    fn index(self) -> &'static LocalKey<RefCell<SortIndex>> {
+        match self {
+            PollSort::CreatedAt => &RECENT_POLLS,
+            PollSort::Deadline => &DEADLINE_POLLS,
+            PollSort::VoteCount => &POPULAR_POLLS,
+        }
+    }
+}
+
+/// Opaque position in a listing, returned as `next_cursor` and passed back
+/// unchanged to fetch the following page.
+#[derive(CandidType, Deserialize, Clone, Copy)]
+struct ListCursor {
+    key: u64,
+    poll_id: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ListPollsArgs {
+    status: Option<PollStatus>,
+    creator: Option<Principal>,
+    sort_by: Option<PollSort>,
+    cursor: Option<ListCursor>,
+    limit: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ListPollsPage {
+    polls: Vec<Poll>,
+    next_cursor: Option<ListCursor>,
+    total: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct OptionResult {
+    option: String,
+    /// Effective votes; selections for most modes, first preferences for
+    /// ranked polls.
+    votes: u64,
+    /// Share of voters who selected this option, or ranked it first; for
+    /// quadratic and weighted polls, share of all effective votes.
+    percentage: f64,
+    /// Voice credits spent on this option, for quadratic polls.
+    credits: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct PollResults {
+    poll_id: u64,
+    voting_mode: VotingMode,
+    options: Vec<OptionResult>,
+    /// Selections across all ballots; one per voter for single-choice and
+    /// ranked polls.
+    total_votes: u64,
+    unique_voters: u64,
+    /// Indices of the leading options, several on a tie. For ranked polls
+    /// these are the winners under the poll's method, known once it closes.
+    winners: Vec<u32>,
+    /// Runoff trace and Borda scores, once a ranked poll has closed.
+    ranked: Option<RankedResults>,
+    /// Histogram and summary statistics of a scale poll.
+    scale: Option<ScaleStats>,
+    /// Secret ballots committed but never revealed.
+    abstentions: Option<u64>,
+    finalized_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct VotesPage {
+    votes: Vec<Vote>,
+    next_cursor: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct PollsPage {
+    polls: Vec<Poll>,
+    next_cursor: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct MyVote {
+    poll: Poll,
+    vote: Vote,
+}
+
+#[derive(CandidType, Deserialize)]
+struct MyVotesPage {
+    votes: Vec<MyVote>,
+    next_cursor: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+struct CreatePollArgs {
+    title: String,
+    description: String,
+    options: Vec<String>,
+    /// When set in the future the poll is created as scheduled and opened by
+    /// the scheduler at that time.
+    start_time: Option<u64>,
+    deadline: Option<u64>,
+    /// Defaults to `SingleChoice`.
+    voting_mode: Option<VotingMode>,
+    /// Defaults to 1.
+    seats: Option<u32>,
+    /// Lets ballots carry a free-text answer.
+    free_text: Option<FreeText>,
+    /// Makes the poll a survey of these questions, which then set their own
+    /// options and voting modes.
+    questions: Option<Vec<Question>>,
+    /// Defaults to `Changeable`.
+    vote_change: Option<VoteChangePolicy>,
+    /// Makes ballots secret: committed while the poll is open and revealed
+    /// within this many nanoseconds after it closes.
+    reveal_window: Option<u64>,
+    /// Defaults to `Public`.
+    ballot_visibility: Option<BallotVisibility>,
+    /// Defaults to `Always`.
+    results_visibility: Option<ResultsVisibility>,
+    /// Makes the poll private; defaults to false.
+    private: Option<bool>,
+    /// Restricts voting to a Merkle-committed list of voters.
+    eligibility: Option<Eligibility>,
+    /// Restricts voting to holders of a token.
+    token_gate: Option<TokenGate>,
+    /// Creates the poll as a draft, seen only by its creator until
+    /// `publish_poll`; defaults to false.
+    draft: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct VoteArgs {
+    poll_id: u64,
+    /// Selected option indices; a single index for single-choice polls.
+    choices: Vec<u32>,
+    /// Votes for each of `choices`, required by quadratic polls.
+    votes: Option<Vec<u32>>,
+    /// The answer to a scale poll, which takes no choices.
+    value: Option<i64>,
+    /// Free-text answer, for polls that take one. Replaces any earlier
+    /// answer; omitting it withdraws that answer.
+    answer: Option<String>,
+    /// Proof that the caller is on the poll's eligibility list, if it has one.
+    proof: Option<EligibilityProof>,
+}
+
+/// Key bounds covering every vote cast in `poll_id`, starting after `cursor`
+/// when one is given.
+fn poll_votes_range(poll_id: u64, cursor: Option<Principal>) -> (Bound<VoteKey>, Bound<VoteKey>) {
+    let start = match cursor {
+        Some(voter) => Bound::Excluded((poll_id, PrincipalKey(voter))),
+        None => Bound::Included((poll_id, PrincipalKey::min())),
+    };
+    (start, Bound::Excluded((poll_id + 1, PrincipalKey::min())))
+}
+
+/// Key bounds covering every poll indexed under `principal`, starting after
+/// the `cursor` poll id when one is given.
+fn principal_polls_range(
+    principal: Principal,
+    cursor: Option<u64>,
+) -> (Bound<PrincipalPollKey>, Bound<PrincipalPollKey>) {
+    let start = match cursor {
+        Some(poll_id) => Bound::Excluded((PrincipalKey(principal), poll_id)),
+        None => Bound::Included((PrincipalKey(principal), 0)),
+    };
+    (start, Bound::Included((PrincipalKey(principal), u64::MAX)))
+}
+
+/// Reads one page of poll ids from a principal index, returning the ids and
+/// the cursor for the next page.
+fn principal_poll_ids(
+    index: &'static LocalKey<RefCell<PollIndex>>,
+    principal: Principal,
+    cursor: Option<u64>,
+    limit: u32,
+) -> (Vec<u64>, Option<u64>) {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+
+    index.with(|index| {
+        let mut ids: Vec<u64> = index.borrow()
+            .range(principal_polls_range(principal, cursor))
+            .take(limit + 1)
+            .map(|((_, poll_id), _)| poll_id)
+            .collect();
+
+        let next_cursor = if ids.len() > limit {
+            ids.truncate(limit);
+            ids.last().copied()
+        } else {
+            None
+        };
+
+        (ids, next_cursor)
+    })
+}
+
+/// Stores the listing summary of poll `id` and moves its entries in every
+/// sort index.
+fn index_poll(id: u64, summary: PollSummary) {
+    let previous = POLL_SUMMARIES.with(|summaries| {
+        summaries.borrow_mut().insert(id, summary.clone())
+    });
+    if let Some(previous) = &previous {
+        count_poll(id, previous, false);
+    }
+    count_poll(id, &summary, true);
+
+    for sort in PollSort::ALL {
+        sort.index().with(|index| {
+            let mut index = index.borrow_mut();
+            if let Some(previous) = &previous {
+                index.remove(&previous.sort_key(id, sort));
+            }
+            index.insert(summary.sort_key(id, sort), ());
+        });
+    }
+}
+
+fn unindex_poll(id: u64) {
+    let Some(summary) = POLL_SUMMARIES.with(|summaries| summaries.borrow_mut().remove(&id)) else {
+        return;
+    };
+    count_poll(id, &summary, false);
+
+    for sort in PollSort::ALL {
+        sort.index().with(|index| {
+            index.borrow_mut().remove(&summary.sort_key(id, sort));
+        });
+    }
+}
+
+/// Adds the poll to, or with `added` unset removes it from, the counters of
+/// its status if it is listed to everyone: drafts and private polls are not.
+fn count_poll(id: u64, summary: &PollSummary, added: bool) {
+    if summary.status == PollStatus::Draft || PRIVATE_POLLS.with(|polls| polls.borrow().contains_key(&id)) {
+        return;
+    }
+    let adjust = |count: Option<u64>| {
+        let count = count.unwrap_or(0);
+        if added { count + 1 } else { count.saturating_sub(1) }
+    };
+    let status = summary.status as u8;
+    POLL_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        let count = adjust(counts.get(&status));
+        counts.insert(status, count);
+    });
+    CREATOR_POLL_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        let key = (PrincipalKey(summary.creator), status);
+        let count = adjust(counts.get(&key));
+        counts.insert(key, count);
+    });
+}
+
+fn update_summary(id: u64, update: impl FnOnce(&mut PollSummary)) {
+    if let Some(mut summary) = POLL_SUMMARIES.with(|summaries| summaries.borrow().get(&id)) {
+        update(&mut summary);
+        index_poll(id, summary);
+    }
+}
+
+// Records that fail to decode are reported as `DecodeFailed` by endpoints that
+// return a `Result`; listings and other plain queries leave them out.
+fn load_poll(id: u64) -> Result<Option<Poll>, PollError> {
+    POLLS.with(|polls| polls.borrow().get(&id))
+        .map(|poll| poll.decode())
+        .transpose()
+}
+
+fn find_poll(id: u64) -> Result<Poll, PollError> {
+    load_poll(id)?.ok_or(PollError::PollNotFound)
+}
+
+fn store_poll(poll: &Poll) {
+    POLLS.with(|polls| polls.borrow_mut().insert(poll.id, Envelope::new(poll)));
+}
+
+fn load_vote(poll_id: u64, voter: Principal) -> Result<Option<Vote>, PollError> {
+    VOTES.with(|votes| votes.borrow().get(&(poll_id, PrincipalKey(voter))))
+        .map(|vote| vote.decode())
+        .transpose()
+}
+
+/// Stores `vote`, returning the raw record it replaced.
+fn store_vote(vote: &Vote) -> Option<Envelope<Vote>> {
+    VOTES.with(|votes| {
+        votes.borrow_mut().insert((vote.poll_id, PrincipalKey(vote.voter)), Envelope::new(vote))
+    })
+}
+
+fn load_credit_tally(poll_id: u64) -> Result<CreditTally, PollError> {
+    CREDIT_TALLIES.with(|tallies| tallies.borrow().get(&poll_id))
+        .map(|tally| tally.decode())
+        .transpose()
+        .map(Option::unwrap_or_default)
+}
+
+fn next_poll_id() -> u64 {
+    NEXT_POLL_ID.with(|next_id| {
+        let mut next_id = next_id.borrow_mut();
+        let id = *next_id.get();
+        next_id.set(id + 1).expect("Failed to persist the poll id counter");
+        id
+    })
+}
+
+#[cfg(not(test))]
+fn is_admin(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal)
+}
+
+/// Native tests have no system API to ask, and no controllers.
+#[cfg(test)]
+fn is_admin(_principal: &Principal) -> bool {
+    false
+}
+
+fn ensure_can_manage(poll: &Poll, caller: Principal) -> Result<(), PollError> {
+    if poll.creator == caller || is_admin(&caller) {
+        Ok(())
+    } else {
+        Err(PollError::NotAuthorized)
+    }
+}
+
+/// Fails unless the poll's results visibility lets the caller see its tallies
+/// and ballots at `now`.
+fn ensure_results_visible(poll: &Poll, caller: Principal, now: u64) -> Result<(), PollError> {
+    if matches!(poll.status_at(now), PollStatus::Closed | PollStatus::Archived) {
+        return Ok(());
+    }
+    let denied = match poll.results_visibility {
+        ResultsVisibility::Always => return Ok(()),
+        ResultsVisibility::AfterVoting => {
+            let key = (PrincipalKey(caller), poll.id);
+            if VOTER_POLLS.with(|index| index.borrow().contains_key(&key)) {
+                return Ok(());
+            }
+            PollError::NotVoted
+        }
+        ResultsVisibility::AfterClose => PollError::ResultsNotFinal,
+    };
+    ensure_can_manage(poll, caller).map_err(|_| denied)
+}
+
+/// Moves a poll to `to` if the caller may manage it and its current status is
+/// one of `from`.
+fn transition_poll(
+    id: u64,
+    from: &[PollStatus],
+    to: PollStatus,
+    caller: Principal,
+    now: u64,
+) -> Result<(), PollError> {
+    let mut poll = find_poll(id)?;
+    ensure_can_manage(&poll, caller)?;
+
+    let current = poll.status_at(now);
+    if !from.contains(&current) {
+        return Err(PollError::InvalidTransition { from: current, to });
+    }
+
+    let was_accepting_votes = matches!(poll.status, PollStatus::Scheduled | PollStatus::Open);
+    poll.closed_at = poll.closed_at_or_deadline(now);
+    if to == PollStatus::Closed {
+        poll.closed_at = Some(now);
+    }
+    poll.status = to;
+    store_poll(&poll);
+    update_summary(id, |summary| summary.status = to);
+
+    // A poll leaving an accepting status through its deadline or an early
+    // close still has to be finalized; cancellation discards the outcome.
+    if was_accepting_votes && poll.closed_at.is_some() && to != PollStatus::Cancelled {
+        on_poll_closed(&poll);
+    }
+    Ok(())
+}
+
+/// Work that must happen once when a poll stops accepting votes, whether it
+/// was closed by the scheduler, by its deadline or early by its creator.
+/// Marks the tally of a poll as final at `at`, unless it already is.
+fn finalize_tally(poll_id: u64, at: u64) {
+    TALLIES.with(|tallies| {
+        let mut tallies = tallies.borrow_mut();
+        let mut tally = tallies.get(&poll_id).unwrap_or_default();
+        if tally.finalized_at.is_none() {
+            tally.finalized_at = Some(at);
+            tallies.insert(poll_id, tally);
+        }
+    });
+}
+
+fn on_poll_closed(poll: &Poll) {
+    let closed_at = poll.closed_at.unwrap_or_else(time);
+    if let Some(window) = poll.reveal_window {
+        // Secret ballots are counted as they are revealed.
+        scheduler::schedule_reveal_end(poll.id, closed_at.saturating_add(window));
+        return;
+    }
+    finalize_tally(poll.id, closed_at);
+
+    if poll.voting_mode.is_ranked() {
+        // Counted a page at a time, outside the message that closes the poll.
+        scheduler::schedule_count(poll.id, closed_at);
+    }
+}
+
+/// Upper bound on the ballots read by one step of a ranked count.
+const MAX_BALLOTS_PER_COUNT: usize = 5_000;
+
+/// A ranked count under way: the distinct rankings read so far, each with the
+/// number of voters who cast it.
+struct RankedCount {
+    last_voter: Principal,
+    rankings: BTreeMap<Vec<u32>, u64>,
+}
+
+thread_local! {
+    /// Ranked counts under way, by poll. Kept on the heap only; a count cut
+    /// short by an upgrade starts over from its persisted schedule entry.
+    static RANKED_COUNTS: RefCell<BTreeMap<u64, RankedCount>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Reads the next `page_size` ballots of a closed ranked poll and, once all
+/// are read, stores its results. Returns whether the count is done.
+fn count_ranked_page(poll: &Poll, page_size: usize) -> bool {
+    let VotingMode::Ranked { method } = poll.voting_mode else {
+        return true;
+    };
+    if RANKED_RESULTS.with(|results| results.borrow().contains_key(&poll.id)) {
+        return true;
+    }
+
+    let (cursor, mut rankings) = RANKED_COUNTS.with(|counts| counts.borrow_mut().remove(&poll.id))
+        .map_or((None, BTreeMap::new()), |count| (Some(count.last_voter), count.rankings));
+    let page: Vec<(VoteKey, Envelope<Vote>)> = VOTES.with(|votes| {
+        votes.borrow()
+            .range(poll_votes_range(poll.id, cursor))
+            .take(page_size)
+            .collect()
+    });
+    // Undecodable ballots are left out rather than blocking the result.
+    for vote in page.iter().filter_map(|(_, vote)| vote.decode().ok()) {
+        *rankings.entry(vote.choices).or_insert(0) += 1;
+    }
+    if let Some(((_, PrincipalKey(last_voter)), _)) = page.last().filter(|_| page.len() == page_size) {
+        let count = RankedCount { last_voter: *last_voter, rankings };
+        RANKED_COUNTS.with(|counts| counts.borrow_mut().insert(poll.id, count));
+        return false;
+    }
+
+    let ballots: Vec<(Vec<u32>, u64)> = rankings.into_iter().collect();
+    let election = (method == RankingMethod::SingleTransferableVote)
+        .then(|| stv::count(poll.id, poll.seats, poll.options.len(), &ballots));
+    let results = RankedResults::compute(method, poll.options.len(), &ballots, election.as_ref());
+    RANKED_RESULTS.with(|cache| {
+        cache.borrow_mut().insert(poll.id, Envelope::new(&results));
+    });
+    if let Some(election) = election {
+        ELECTION_REPORTS.with(|reports| {
+            reports.borrow_mut().insert(poll.id, Envelope::new(&election));
+        });
+    }
+    true
+}
+
+#[ic_cdk::query]
+fn get_poll(id: u64) -> Option<Poll> {
+    let caller = ic_cdk::caller();
+    let now = time();
+
+    load_poll(id)
+        .ok()
+        .flatten()
+        .filter(|poll| poll.is_visible_to(caller))
+        .map(|poll| poll.observed_at(now))
+}
+
+/// Returns every listed poll in one response; prefer `list_polls`, which pages.
+#[ic_cdk::query]
+fn get_polls() -> Vec<Poll> {
+    let caller = ic_cdk::caller();
+    let now = time();
+
+    POLLS.with(|polls| {
+        polls.borrow()
+            .iter()
+            .filter_map(|(_, poll)| poll.decode().ok())
+            .filter(|poll| poll.status != PollStatus::Archived && poll.is_visible_to(caller))
+            .map(|poll| poll.observed_at(now))
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+fn create_poll(args: CreatePollArgs) -> Result<u64, PollError> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    load_config().validate_poll(&args, now)?;
+    let id = next_poll_id();
+    let status = if args.draft.unwrap_or(false) {
+        PollStatus::Draft
+    } else {
+        published_status(args.start_time, now)
+    };
+
+    let poll = Poll {
+        id,
+        title: args.title,
+        description: args.description,
+        options: args.options,
+        creator: caller,
+        created_at: now,
+        start_time: args.start_time,
+        deadline: args.deadline,
+        status,
+        closed_at: None,
+        rewards: None,
+        voting_mode: args.voting_mode.unwrap_or_default(),
+        seats: args.seats.unwrap_or(1),
+        free_text: args.free_text,
+        questions: args.questions.unwrap_or_default(),
+        vote_change: args.vote_change.unwrap_or_default(),
+        reveal_window: args.reveal_window,
+        ballot_visibility: args.ballot_visibility.unwrap_or_default(),
+        results_visibility: args.results_visibility.unwrap_or_default(),
+        private: args.private.unwrap_or(false),
+        eligibility: args.eligibility,
+        token_gate: args.token_gate,
+    };
+
+    insert_poll(poll, 0);
+    scheduler::arm();
+
+    Ok(id)
+}
+
+/// Status a poll takes when it is published at `now`.
+fn published_status(start_time: Option<u64>, now: u64) -> PollStatus {
+    match start_time {
+        Some(start_time) if start_time > now => PollStatus::Scheduled,
+        _ => PollStatus::Open,
+    }
+}
+
+/// Stores a new poll with its listing, creator and scheduler entries.
+fn insert_poll(poll: Poll, votes: u64) {
+    // Marked first: private polls are left out of the listing counters, and
+    // sealed ones out of the vote count order.
+    if poll.private {
+        PRIVATE_POLLS.with(|polls| polls.borrow_mut().insert(poll.id, ()));
+    }
+    if poll.results_visibility != ResultsVisibility::Always {
+        SEALED_POLLS.with(|polls| polls.borrow_mut().insert(poll.id, ()));
+    }
+    index_poll(poll.id, PollSummary::new(&poll, votes));
+    scheduler::schedule(&poll);
+
+    CREATOR_POLLS.with(|index| {
+        index.borrow_mut().insert((PrincipalKey(poll.creator), poll.id), ());
+    });
+
+    store_poll(&poll);
+}
+
+fn load_config() -> PollConfig {
+    CONFIG.with(|config| config.borrow().get().decode())
+        .expect("Failed to decode the poll config")
+}
+
+#[ic_cdk::query]
+fn get_config() -> PollConfig {
+    load_config()
+}
+
+/// Replaces the poll creation limits; controllers only.
+#[ic_cdk::update]
+fn set_config(new_config: PollConfig) -> Result<(), PollError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(PollError::NotAuthorized);
+    }
+    new_config.validate()?;
+
+    CONFIG.with(|config| {
+        config.borrow_mut()
+            .set(Envelope::new(&new_config))
+            .expect("Failed to persist the poll config");
+    });
+    Ok(())
+}
+
+/// Opens a draft for voting, or schedules it if its start time is still ahead.
+#[ic_cdk::update]
+fn publish_poll(id: u64) -> Result<(), PollError> {
+    publish(id, ic_cdk::caller(), time())?;
+    scheduler::arm();
+    Ok(())
+}
+
+fn publish(id: u64, caller: Principal, now: u64) -> Result<(), PollError> {
+    let mut poll = find_poll(id)?;
+    ensure_can_manage(&poll, caller)?;
+
+    let status = published_status(poll.start_time, now);
+    if poll.status != PollStatus::Draft {
+        return Err(PollError::InvalidTransition { from: poll.status_at(now), to: status });
+    }
+    // The deadline was checked when the draft was created and may have passed since.
+    if poll.deadline.is_some_and(|deadline| deadline <= now) {
+        return Err(PollError::DeadlineInPast);
+    }
+
+    poll.status = status;
+    store_poll(&poll);
+    update_summary(id, |summary| summary.status = status);
+    scheduler::schedule(&poll);
+    Ok(())
+}
+
+/// Ends voting before the deadline.
+#[ic_cdk::update]
+fn close_poll(id: u64) -> Result<(), PollError> {
+    transition_poll(id, &[PollStatus::Open], PollStatus::Closed, ic_cdk::caller(), time())
+}
+
+/// Aborts a poll that has not finished; its votes are kept but no longer count.
+#[ic_cdk::update]
+fn cancel_poll(id: u64) -> Result<(), PollError> {
+    transition_poll(
+        id,
+        &[PollStatus::Draft, PollStatus::Scheduled, PollStatus::Open],
+        PollStatus::Cancelled,
+        ic_cdk::caller(),
+        time(),
+    )
+}
+
+/// Hides a finished poll from listings while keeping it retrievable by id.
+#[ic_cdk::update]
+fn archive_poll(id: u64) -> Result<(), PollError> {
+    transition_poll(
+        id,
+        &[PollStatus::Closed, PollStatus::Cancelled],
+        PollStatus::Archived,
+        ic_cdk::caller(),
+        time(),
+    )
+}
+
+/// Permanently removes a poll together with all of its votes. The poll is
+/// gone at once; its ballots and other per-voter records are removed a batch
+/// at a time by the scheduler.
+#[ic_cdk::update]
+fn delete_poll(id: u64) -> Result<(), PollError> {
+    remove_poll(id, ic_cdk::caller(), time())?;
+    scheduler::arm();
+    Ok(())
+}
+
+/// Upper bound on the per-voter records of a deleted poll removed per
+/// scheduler step, so deleting a large poll cannot exhaust the instruction
+/// limit.
+const MAX_RECORDS_PER_PURGE: usize = 5_000;
+
+fn remove_poll(id: u64, caller: Principal, now: u64) -> Result<(), PollError> {
+    let poll = find_poll(id)?;
+    ensure_can_manage(&poll, caller)?;
+    POLLS.with(|polls| polls.borrow_mut().remove(&id));
+
+    unindex_poll(id);
+
+    CREATOR_POLLS.with(|index| {
+        index.borrow_mut().remove(&(PrincipalKey(poll.creator), id));
+    });
+    PRIVATE_POLLS.with(|polls| polls.borrow_mut().remove(&id));
+    SEALED_POLLS.with(|polls| polls.borrow_mut().remove(&id));
+    // Legacy ids are few: the heap canister they come from held every poll.
+    let legacy_id = LEGACY_POLL_IDS.with(|ids| {
+        ids.borrow().iter().find(|&(_, poll_id)| poll_id == id).map(|(legacy_id, _)| legacy_id)
+    });
+    if let Some(legacy_id) = legacy_id {
+        LEGACY_POLL_IDS.with(|ids| ids.borrow_mut().remove(&legacy_id));
+    }
+
+    TALLIES.with(|tallies| {
+        tallies.borrow_mut().remove(&id);
+    });
+    RANKED_RESULTS.with(|results| {
+        results.borrow_mut().remove(&id);
+    });
+    RANKED_COUNTS.with(|counts| {
+        counts.borrow_mut().remove(&id);
+    });
+    ELECTION_REPORTS.with(|reports| {
+        reports.borrow_mut().remove(&id);
+    });
+    CREDIT_TALLIES.with(|tallies| {
+        tallies.borrow_mut().remove(&id);
+    });
+    SURVEY_TALLIES.with(|tallies| {
+        let mut tallies = tallies.borrow_mut();
+        let keys: Vec<_> = tallies.range(survey::question_tallies_range(id))
+            .map(|(key, _)| key)
+            .collect();
+        for key in &keys {
+            tallies.remove(key);
+        }
+    });
+
+    scheduler::unschedule(&poll);
+    scheduler::schedule_purge(id, now);
+    Ok(())
+}
+
+/// Removes up to `budget` of a deleted poll's ballots, answers, survey
+/// responses, commitments, members and invites. Returns whether none are left.
+fn purge_page(poll_id: u64, budget: usize) -> bool {
+    let mut budget = budget;
+    let unindex = |keys: Vec<VoteKey>| {
+        VOTER_POLLS.with(|index| {
+            let mut index = index.borrow_mut();
+            for (_, voter) in keys {
+                index.remove(&(voter, poll_id));
+            }
+        });
+    };
+    unindex(VOTES.with(|votes| {
+        drain_range(&mut votes.borrow_mut(), poll_votes_range(poll_id, None), &mut budget)
+    }));
+    ANSWERS.with(|answers| {
+        drain_range(&mut answers.borrow_mut(), poll_votes_range(poll_id, None), &mut budget)
+    });
+    unindex(SURVEY_RESPONSES.with(|responses| {
+        drain_range(&mut responses.borrow_mut(), poll_votes_range(poll_id, None), &mut budget)
+    }));
+    unindex(COMMITMENTS.with(|commitments| {
+        drain_range(&mut commitments.borrow_mut(), poll_votes_range(poll_id, None), &mut budget)
+    }));
+    membership::purge(poll_id, &mut budget);
+    budget > 0
+}
+
+/// Removes up to `budget` entries of `map` in `range`, returning their keys.
+fn drain_range<K, V>(
+    map: &mut StableBTreeMap<K, V, Memory>,
+    range: impl std::ops::RangeBounds<K>,
+    budget: &mut usize,
+) -> Vec<K>
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    let keys: Vec<K> = map.range(range).take(*budget).map(|(key, _)| key).collect();
+    for key in &keys {
+        map.remove(key);
+    }
+    *budget -= keys.len();
+    keys
+}
+
+/// Adds principals to a private poll's allowlist.
+#[ic_cdk::update]
+fn add_members(poll_id: u64, principals: Vec<Principal>) -> Result<(), PollError> {
+    membership::add_members(poll_id, principals, ic_cdk::caller())
+}
+
+#[ic_cdk::update]
+fn remove_members(poll_id: u64, principals: Vec<Principal>) -> Result<(), PollError> {
+    membership::remove_members(poll_id, principals, ic_cdk::caller())
+}
+
+/// Pages through a private poll's members, for its managers.
+#[ic_cdk::query]
+fn get_members(poll_id: u64, cursor: Option<Principal>, limit: u32) -> Result<MembersPage, PollError> {
+    membership::members(poll_id, cursor, limit, ic_cdk::caller())
+}
+
+/// Creates an invite code to a private poll.
+#[ic_cdk::update]
+async fn create_invite(args: CreateInviteArgs) -> Result<String, PollError> {
+    let caller = ic_cdk::caller();
+    // Checked before the call too, so callers who may not invite cannot make
+    // the canister pay for randomness; checked again after it returns.
+    membership::check_invite(&args, caller, time())?;
+    let (random,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(token::call_failed)?;
+    // `raw_rand` returns 32 bytes, more than a code takes.
+    let code: InviteCode = random[..std::mem::size_of::<InviteCode>()]
+        .try_into()
+        .expect("raw_rand returned too few bytes");
+
+    membership::create_invite(args, code, caller, time())
+}
+
+#[ic_cdk::update]
+fn revoke_invite(poll_id: u64, code: String) -> Result<(), PollError> {
+    membership::revoke_invite(poll_id, &code, ic_cdk::caller())
+}
+
+#[ic_cdk::query]
+fn get_invites(poll_id: u64) -> Result<Vec<InviteInfo>, PollError> {
+    membership::invites(poll_id, ic_cdk::caller())
+}
+
+/// Joins a private poll with an invite code.
+#[ic_cdk::update]
+fn redeem_invite(poll_id: u64, code: String) -> Result<(), PollError> {
+    membership::redeem(poll_id, &code, ic_cdk::caller(), time())
+}
+
+#[ic_cdk::update]
+async fn vote(args: VoteArgs) -> Result<(), PollError> {
+    let caller = ic_cdk::caller();
+    // Ballots that fail without the balances are turned away before any call.
+    // The poll may change while the calls are in flight, so everything is
+    // checked again once they return.
+    check_ballot(&args, caller, time(), None, false)?;
+    let locked = locked_credits(args.poll_id, caller).await?;
+    let balance = gated_balance(args.poll_id, caller).await?;
+    cast_vote(args, caller, time(), locked, balance, false)
+}
+
+/// Replaces the caller's ballot, in polls whose policy allows it.
+#[ic_cdk::update]
+async fn change_vote(args: VoteArgs) -> Result<(), PollError> {
+    let caller = ic_cdk::caller();
+    check_ballot(&args, caller, time(), None, true)?;
+    let locked = locked_credits(args.poll_id, caller).await?;
+    let balance = gated_balance(args.poll_id, caller).await?;
+    cast_vote(args, caller, time(), locked, balance, true)
+}
+
+/// Withdraws the caller's ballot, and any free-text answer with it, or their
+/// survey response from a revocable poll.
+#[ic_cdk::update]
+fn revoke_vote(poll_id: u64) -> Result<(), PollError> {
+    withdraw_vote(poll_id, ic_cdk::caller(), time())
+}
+
+/// The voter's balance of the token gating the poll, if it is token-gated.
+async fn gated_balance(poll_id: u64, voter: Principal) -> Result<Option<u64>, PollError> {
+    let Some(gate) = load_poll(poll_id)?.and_then(|poll| poll.token_gate) else {
+        return Ok(None);
+    };
+    gate.balance(load_config().pps_token, voter).await.map(Some)
+}
+
+/// PPS `voter` has locked for a PPS-backed quadratic poll, which caps their
+/// credit budget. `None` for every other poll.
+async fn locked_credits(poll_id: u64, voter: Principal) -> Result<Option<u64>, PollError> {
+    let Some(poll) = load_poll(poll_id)? else {
+        return Ok(None);
+    };
+    if !poll.voting_mode.is_pps_backed() {
+        return Ok(None);
+    }
+    let token = load_config().pps_token.ok_or_else(|| PollError::CanisterCallFailed {
+        reason: "no PPS token canister is configured".to_string(),
+    })?;
+
+    match (token::get_lock(token, voter, poll_id).await?, poll.deadline) {
+        (Some(lock), Some(deadline)) if lock.until < deadline => {
+            Err(PollError::LockTooShort { deadline })
+        }
+        (lock, _) => Ok(Some(lock.map_or(0, |lock| lock.amount))),
+    }
+}
+
+/// Fails unless `poll` accepts ballots at `now`.
+fn ensure_open(poll: &Poll, now: u64) -> Result<(), PollError> {
+    match poll.status_at(now) {
+        PollStatus::Open => Ok(()),
+        PollStatus::Closed => {
+            let closed_at = poll.closed_at_or_deadline(now).unwrap_or(now);
+            Err(PollError::PollClosed { closed_at })
+        }
+        status => Err(PollError::PollNotOpen { status }),
+    }
+}
+
+/// A ballot that passed every check that needs no other canister, with its
+/// poll and the ballot it replaces.
+struct CheckedBallot {
+    poll: Poll,
+    previous: Option<Vote>,
+    vote: Vote,
+}
+
+/// Checks a ballot against its poll: a first ballot, or one that replaces the
+/// caller's ballot when `replace` is set. `locked` caps the credit budget of
+/// quadratic polls.
+fn check_ballot(
+    args: &VoteArgs,
+    caller: Principal,
+    now: u64,
+    locked: Option<u64>,
+    replace: bool,
+) -> Result<CheckedBallot, PollError> {
+    let poll = find_poll(args.poll_id)?;
+    if !poll.is_visible_to(caller) {
+        return Err(PollError::PollNotFound);
+    }
+    ensure_open(&poll, now)?;
+    if !poll.questions.is_empty() {
+        return Err(PollError::InvalidVotingMode {
+            reason: "surveys are answered with submit_survey".to_string(),
+        });
+    }
+    if poll.reveal_window.is_some() {
+        return Err(PollError::InvalidVotingMode {
+            reason: "secret ballots are cast with commit_vote".to_string(),
+        });
+    }
+    if replace && !poll.vote_change.allows_change() {
+        return Err(PollError::VoteChangeNotAllowed);
+    }
+
+    // Decoded before anything is written: the tally corrections need it.
+    let previous = load_vote(args.poll_id, caller)?;
+    match (&previous, replace) {
+        (Some(_), false) => return Err(PollError::AlreadyVoted),
+        (None, true) => return Err(PollError::NotVoted),
+        _ => {}
+    }
+    let weight = match &poll.eligibility {
+        Some(eligibility) => eligibility.verify(caller, args.proof.as_ref())?,
+        None => 1,
+    };
+    if let Some(gate) = &poll.token_gate {
+        gate.check_token(&load_config())?;
+    }
+    match (poll.free_text, &args.answer) {
+        (Some(free_text), Some(answer)) => free_text.check(answer)?,
+        (None, Some(_)) => {
+            return Err(PollError::InvalidAnswer {
+                reason: "this poll does not take free-text answers".to_string(),
+            });
+        }
+        (_, None) => {}
+    }
+
+    let vote = Vote {
+        poll_id: args.poll_id,
+        voter: caller,
+        choices: args.choices.clone(),
+        votes: args.votes.clone().unwrap_or_default(),
+        value: args.value,
+        voted_at: now,
+        revision: previous.as_ref().map_or(0, |previous| previous.revision + 1),
+        weight,
+    };
+    poll.voting_mode.validate_ballot(poll.options.len(), &vote)?;
+    if let VotingMode::Quadratic { budget, .. } = poll.voting_mode {
+        let budget = locked.map_or(budget, |locked| budget.min(locked));
+        let cost = voting::quadratic_cost(&vote.votes);
+        if cost > budget {
+            return Err(PollError::BudgetExceeded { cost, budget });
+        }
+    }
+
+    Ok(CheckedBallot { poll, previous, vote })
+}
+
+/// Validates and records a ballot: a first ballot, or one that replaces the
+/// caller's ballot when `replace` is set.
+fn cast_vote(
+    args: VoteArgs,
+    caller: Principal,
+    now: u64,
+    locked: Option<u64>,
+    balance: Option<u64>,
+    replace: bool,
+) -> Result<(), PollError> {
+    let CheckedBallot { poll, previous, vote } = check_ballot(&args, caller, now, locked, replace)?;
+    if let Some(gate) = &poll.token_gate {
+        gate.check(balance)?;
+    }
+
+    let credit_tally = match poll.voting_mode {
+        VotingMode::Quadratic { .. } => Some(load_credit_tally(args.poll_id)?),
+        _ => None,
+    };
+
+    store_vote(&vote);
+
+    VOTER_POLLS.with(|index| {
+        index.borrow_mut().insert((PrincipalKey(caller), args.poll_id), ());
+    });
+
+    ANSWERS.with(|answers| {
+        let mut answers = answers.borrow_mut();
+        let key = (args.poll_id, PrincipalKey(caller));
+        match args.answer {
+            Some(text) => answers.insert(key, Envelope::new(&Answer::new(caller, text, now))),
+            None => answers.remove(&key),
+        };
+    });
+
+    let voters = TALLIES.with(|tallies| {
+        let mut tallies = tallies.borrow_mut();
+        let mut tally = tallies.get(&args.poll_id).unwrap_or_default();
+        let previous = previous.as_ref().map(|vote| poll.voting_mode.counted(vote));
+        tally.record(
+            poll.voting_mode.tally_width(poll.options.len()),
+            previous.as_deref(),
+            &poll.voting_mode.counted(&vote),
+        );
+        let voters = tally.unique_voters;
+        tallies.insert(args.poll_id, tally);
+        voters
+    });
+
+    if let Some(mut credit_tally) = credit_tally {
+        credit_tally.record(poll.options.len(), previous.as_ref(), &vote);
+        CREDIT_TALLIES.with(|tallies| {
+            tallies.borrow_mut().insert(args.poll_id, Envelope::new(&credit_tally));
+        });
+    }
+
+    update_summary(args.poll_id, |summary| summary.votes = voters);
+
+    Ok(())
+}
+
+fn withdraw_vote(poll_id: u64, caller: Principal, now: u64) -> Result<(), PollError> {
+    let poll = find_poll(poll_id)?;
+    if !poll.is_visible_to(caller) {
+        return Err(PollError::PollNotFound);
+    }
+    ensure_open(&poll, now)?;
+    if poll.vote_change != VoteChangePolicy::Revocable {
+        return Err(PollError::VoteChangeNotAllowed);
+    }
+    if !poll.questions.is_empty() {
+        return survey::withdraw(&poll, caller);
+    }
+
+    let previous = load_vote(poll_id, caller)?.ok_or(PollError::NotVoted)?;
+    let credit_tally = match poll.voting_mode {
+        VotingMode::Quadratic { .. } => Some(load_credit_tally(poll_id)?),
+        _ => None,
+    };
+
+    let key = (poll_id, PrincipalKey(caller));
+    VOTES.with(|votes| votes.borrow_mut().remove(&key));
+    ANSWERS.with(|answers| answers.borrow_mut().remove(&key));
+    VOTER_POLLS.with(|index| index.borrow_mut().remove(&(PrincipalKey(caller), poll_id)));
+
+    let voters = TALLIES.with(|tallies| {
+        let mut tallies = tallies.borrow_mut();
+        let mut tally = tallies.get(&poll_id).unwrap_or_default();
+        tally.withdraw(&poll.voting_mode.counted(&previous));
+        let voters = tally.unique_voters;
+        tallies.insert(poll_id, tally);
+        voters
+    });
+
+    if let Some(mut credit_tally) = credit_tally {
+        credit_tally.withdraw(&previous);
+        CREDIT_TALLIES.with(|tallies| {
+            tallies.borrow_mut().insert(poll_id, Envelope::new(&credit_tally));
+        });
+    }
+
+    update_summary(poll_id, |summary| summary.votes = voters);
+
+    Ok(())
+}
+
+/// Returns up to `limit` votes of a poll ordered by voter, resuming after the
+/// `cursor` voter returned by the previous page, to the callers the poll's
+/// ballot visibility allows. Secret polls list nothing until their reveal
+/// window ends.
+#[ic_cdk::query]
+fn get_votes(poll_id: u64, cursor: Option<Principal>, limit: u32) -> Result<VotesPage, PollError> {
+    votes_page(poll_id, cursor, limit, ic_cdk::caller(), time())
+}
+
+fn votes_page(
+    poll_id: u64,
+    cursor: Option<Principal>,
+    limit: u32,
+    caller: Principal,
+    now: u64,
+) -> Result<VotesPage, PollError> {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+
+    let poll = find_poll(poll_id)?;
+    if !poll.is_visible_to(caller) {
+        return Err(PollError::PollNotFound);
+    }
+    match poll.ballot_visibility {
+        BallotVisibility::Public => {}
+        BallotVisibility::CreatorOnly => ensure_can_manage(&poll, caller)?,
+        BallotVisibility::AggregateOnly => return Err(PollError::NotAuthorized),
+    }
+    ensure_results_visible(&poll, caller, now)?;
+    secret::ensure_revealed(&poll, now)?;
+
+    Ok(VOTES.with(|votes| {
+        let votes = votes.borrow();
+        let mut page: Vec<_> = votes.range(poll_votes_range(poll_id, cursor))
+            .take(limit + 1)
+            .collect();
+
+        let next_cursor = if page.len() > limit {
+            page.truncate(limit);
+            page.last().map(|((_, voter), _)| voter.0)
+        } else {
+            None
+        };
+        let page = page.into_iter()
+            .filter_map(|(_, vote)| vote.decode().ok())
+            .collect();
+
+        VotesPage { votes: page, next_cursor }
+    }))
+}
+
+/// The caller's own ballot in a poll, whatever its ballot visibility.
+#[ic_cdk::query]
+fn get_my_vote(poll_id: u64) -> Result<Option<Vote>, PollError> {
+    let caller = ic_cdk::caller();
+
+    let poll = find_poll(poll_id)?;
+    if !poll.is_visible_to(caller) {
+        return Err(PollError::PollNotFound);
+    }
+    load_vote(poll_id, caller)
+}
+
+/// Returns up to `limit` free-text answers of a poll ordered by voter,
+/// resuming after the `cursor` voter returned by the previous page, to the
+/// callers the poll's ballot and results visibility allow. Poll managers see every answer
+/// at any time; everyone else sees the answers that are not hidden, once the
+/// poll has closed if it holds them until then. Secret polls list nothing
+/// until their reveal window ends.
+#[ic_cdk::query]
+fn get_answers(poll_id: u64, cursor: Option<Principal>, limit: u32) -> Result<AnswersPage, PollError> {
+    answers_page(poll_id, cursor, limit, ic_cdk::caller(), time())
+}
+
+fn answers_page(
+    poll_id: u64,
+    cursor: Option<Principal>,
+    limit: u32,
+    caller: Principal,
+    now: u64,
+) -> Result<AnswersPage, PollError> {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+
+    let poll = find_poll(poll_id)?;
+    if !poll.is_visible_to(caller) {
+        return Err(PollError::PollNotFound);
+    }
+    match poll.ballot_visibility {
+        BallotVisibility::Public => {}
+        BallotVisibility::CreatorOnly => ensure_can_manage(&poll, caller)?,
+        BallotVisibility::AggregateOnly => return Err(PollError::NotAuthorized),
+    }
+    ensure_results_visible(&poll, caller, now)?;
+    let manager = ensure_can_manage(&poll, caller).is_ok();
+    let held = poll.free_text.is_some_and(|free_text| free_text.hidden_until_closed);
+    let closed = matches!(poll.status_at(now), PollStatus::Closed | PollStatus::Archived);
+    if held && !closed && !manager {
+        return Err(PollError::ResultsNotFinal);
+    }
+    secret::ensure_revealed(&poll, now)?;
+
+    // An answer that fails to decode is skipped rather than failing the page.
+    let mut answers: Vec<Answer> = ANSWERS.with(|answers| {
+        answers.borrow()
+            .range(poll_votes_range(poll_id, cursor))
+            .filter_map(|(_, answer)| answer.decode().ok())
+            .filter(|answer| !answer.hidden || manager)
+            .take(limit + 1)
+            .collect()
+    });
+
+    let next_cursor = if answers.len() > limit {
+        answers.truncate(limit);
+        answers.last().map(|answer| answer.voter)
+    } else {
+        None
+    };
+    Ok(AnswersPage { answers, next_cursor })
+}
+
+/// Hides a free-text answer from everyone but the poll's managers, or shows
+/// it again.
+#[ic_cdk::update]
+fn moderate_answer(poll_id: u64, voter: Principal, hidden: bool) -> Result<(), PollError> {
+    let caller = ic_cdk::caller();
+
+    let poll = find_poll(poll_id)?;
+    ensure_can_manage(&poll, caller)?;
+
+    let key = (poll_id, PrincipalKey(voter));
+    let mut answer = ANSWERS.with(|answers| answers.borrow().get(&key))
+        .ok_or(PollError::AnswerNotFound)?
+        .decode()?;
+    answer.hidden = hidden;
+    ANSWERS.with(|answers| answers.borrow_mut().insert(key, Envelope::new(&answer)));
+
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_results(poll_id: u64) -> Result<PollResults, PollError> {
+    let caller = ic_cdk::caller();
+
+    let poll = find_poll(poll_id)?;
+    if !poll.is_visible_to(caller) {
+        return Err(PollError::PollNotFound);
+    }
+    let now = time();
+    ensure_results_visible(&poll, caller, now)?;
+    secret::ensure_revealed(&poll, now)?;
+    let tally = TALLIES.with(|tallies| tallies.borrow().get(&poll_id)).unwrap_or_default();
+
+    let ranked = match poll.voting_mode {
+        VotingMode::Ranked { .. } => {
+            RANKED_RESULTS.with(|results| results.borrow().get(&poll_id))
+                .map(|results| results.decode())
+                .transpose()?
+        }
+        _ => None,
+    };
+    let mut counts = tally.counts;
+    counts.resize(poll.voting_mode.tally_width(poll.options.len()), 0);
+    let scale = match poll.voting_mode {
+        VotingMode::Scale { min, step, .. } => Some(ScaleStats::from_counts(min, step, &counts)),
+        _ => None,
+    };
+    let winners = match &ranked {
+        Some(ranked) => ranked.winners.clone(),
+        None if poll.voting_mode.is_ranked() || scale.is_some() => Vec::new(),
+        None => voting::leaders(&counts),
+    };
+    let credits = match poll.voting_mode {
+        VotingMode::Quadratic { .. } => {
+            let mut credits = load_credit_tally(poll_id)?.credits;
+            credits.resize(poll.options.len(), 0);
+            Some(credits)
+        }
+        _ => None,
+    };
+    let total_votes: u64 = counts.iter().sum();
+    let voters = tally.unique_voters;
+    let weighted = poll.eligibility.as_ref().is_some_and(|eligibility| eligibility.weighted);
+    let base = if credits.is_some() || weighted { total_votes } else { voters };
+    let options = option_results(poll.options, &counts, credits.as_deref(), base);
+
+    Ok(PollResults {
+        poll_id,
+        voting_mode: poll.voting_mode,
+        options,
+        total_votes,
+        unique_voters: voters,
+        winners,
+        ranked,
+        scale,
+        abstentions: poll.reveal_window.map(|_| secret::abstentions(poll_id)),
+        finalized_at: tally.finalized_at,
+    })
+}
+
+/// Pairs each option with its tallied votes and their share of `base`.
+fn option_results(
+    options: Vec<String>,
+    counts: &[u64],
+    credits: Option<&[u64]>,
+    base: u64,
+) -> Vec<OptionResult> {
+    options.into_iter()
+        .zip(counts)
+        .enumerate()
+        .map(|(index, (option, &votes))| {
+            let percentage = if base == 0 {
+                0.0
+            } else {
+                votes as f64 * 100.0 / base as f64
+            };
+            let credits = credits.map(|credits| credits[index]);
+            OptionResult { option, votes, percentage, credits }
+        })
+        .collect()
+}
+
+/// Commits to a secret ballot; see `secret::commit`.
+#[ic_cdk::update]
+fn commit_vote(args: CommitArgs) -> Result<(), PollError> {
+    secret::commit(args, ic_cdk::caller(), time())
+}
+
+/// Reveals a committed secret ballot during the poll's reveal window.
+#[ic_cdk::update]
+fn reveal_vote(args: RevealArgs) -> Result<(), PollError> {
+    secret::reveal(args, ic_cdk::caller(), time())
+}
+
+/// Records answers to every question of a survey at once; see
+/// `survey::submit`.
+#[ic_cdk::update]
+fn submit_survey(args: SubmitSurveyArgs) -> Result<(), PollError> {
+    survey::submit(args, ic_cdk::caller(), time())
+}
+
+/// Per-question results of a survey.
+#[ic_cdk::query]
+fn get_survey_results(poll_id: u64) -> Result<SurveyResults, PollError> {
+    let caller = ic_cdk::caller();
+
+    let poll = find_poll(poll_id)?;
+    if !poll.is_visible_to(caller) {
+        return Err(PollError::PollNotFound);
+    }
+    if poll.questions.is_empty() {
+        return Err(PollError::InvalidVotingMode { reason: "the poll is not a survey".to_string() });
+    }
+    ensure_results_visible(&poll, caller, time())?;
+    Ok(survey::results(poll))
+}
+
+/// Round-by-round count of a single transferable vote poll, available once
+/// the poll has closed.
+#[ic_cdk::query]
+fn get_election_report(poll_id: u64) -> Result<ElectionReport, PollError> {
+    let caller = ic_cdk::caller();
+
+    let poll = find_poll(poll_id)?;
+    if !poll.is_visible_to(caller) {
+        return Err(PollError::PollNotFound);
+    }
+    if !poll.voting_mode.is_stv() {
+        return Err(PollError::InvalidVotingMode {
+            reason: "poll does not use single transferable vote".to_string(),
+        });
+    }
+    ensure_results_visible(&poll, caller, time())?;
+
+    ELECTION_REPORTS.with(|reports| reports.borrow().get(&poll_id))
+        .ok_or(PollError::ResultsNotFinal)?
+        .decode()
+}
+
+/// Polls created by the caller, oldest first, including drafts and archived polls.
+#[ic_cdk::query]
+fn get_my_polls(cursor: Option<u64>, limit: u32) -> PollsPage {
+    my_polls(ic_cdk::caller(), cursor, limit, time())
+}
+
+fn my_polls(caller: Principal, cursor: Option<u64>, limit: u32, now: u64) -> PollsPage {
+    let (ids, next_cursor) = principal_poll_ids(&CREATOR_POLLS, caller, cursor, limit);
+
+    let polls = ids.into_iter()
+        .filter_map(|id| load_poll(id).ok().flatten())
+        .map(|poll| poll.observed_at(now))
+        .collect();
+
+    PollsPage { polls, next_cursor }
+}
+
+/// Polls the caller voted in, oldest first, together with the caller's vote.
+#[ic_cdk::query]
+fn get_my_votes(cursor: Option<u64>, limit: u32) -> MyVotesPage {
+    my_votes(ic_cdk::caller(), cursor, limit, time())
+}
+
+fn my_votes(caller: Principal, cursor: Option<u64>, limit: u32, now: u64) -> MyVotesPage {
+    let (ids, next_cursor) = principal_poll_ids(&VOTER_POLLS, caller, cursor, limit);
+
+    let votes = ids.into_iter()
+        .filter_map(|id| {
+            let poll = load_poll(id).ok()??;
+            let vote = load_vote(id, caller).ok()??;
+            Some(MyVote { poll: poll.observed_at(now), vote })
+        })
+        .collect();
+
+    MyVotesPage { votes, next_cursor }
+}
+
+/// Pages through polls in the requested order, filtered by effective status
+/// and creator. Without a status filter archived polls are left out; drafts
+/// are only listed for their creator. A page may hold fewer than `limit`
+/// polls, or none, while `next_cursor` is set: each call walks a bounded
+/// stretch of the index.
+#[ic_cdk::query]
+fn list_polls(args: ListPollsArgs) -> ListPollsPage {
+    list(&args, ic_cdk::caller(), time())
+}
+
+fn list(args: &ListPollsArgs, caller: Principal, now: u64) -> ListPollsPage {
+    let sort = args.sort_by.unwrap_or(PollSort::CreatedAt);
+    let limit = args.limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let start = match args.cursor {
+        Some(cursor) => Bound::Excluded((cursor.key, cursor.poll_id)),
+        None => Bound::Unbounded,
+    };
+
+    let (mut page, scanned, last_scanned) = POLL_SUMMARIES.with(|summaries| {
+        let summaries = summaries.borrow();
+        sort.index().with(|index| {
+            let mut page: Vec<SortKey> = Vec::new();
+            let mut scanned = 0;
+            let mut last_scanned = None;
+            for (key, _) in index.borrow().range((start, Bound::Unbounded)) {
+                if page.len() > limit || scanned == MAX_SCANNED_PER_PAGE {
+                    break;
+                }
+                scanned += 1;
+                last_scanned = Some(key);
+                if summaries.get(&key.1).is_some_and(|summary| summary.matches(key.1, args, caller, now)) {
+                    page.push(key);
+                }
+            }
+            (page, scanned, last_scanned)
+        })
+    });
+
+    let next_cursor = if page.len() > limit {
+        page.truncate(limit);
+        page.last().copied()
+    } else if scanned == MAX_SCANNED_PER_PAGE {
+        last_scanned
+    } else {
+        None
+    };
+
+    let polls = page.into_iter()
+        .filter_map(|(_, id)| load_poll(id).ok().flatten())
+        .map(|poll| poll.observed_at(now))
+        .collect();
+
+    ListPollsPage {
+        polls,
+        next_cursor: next_cursor.map(|(key, poll_id)| ListCursor { key, poll_id }),
+        total: count_polls(args),
+    }
+}
+
+/// Number of polls listed to everyone that match the listing filters, read
+/// from the per-status counters. The caller's own drafts and the private
+/// polls they may see are listed but not counted, and a poll is counted under
+/// its stored status until the scheduler persists a due transition.
+fn count_polls(args: &ListPollsArgs) -> u64 {
+    let statuses = [
+        PollStatus::Scheduled,
+        PollStatus::Open,
+        PollStatus::Closed,
+        PollStatus::Cancelled,
+        PollStatus::Archived,
+    ];
+    statuses.into_iter()
+        .filter(|&status| match args.status {
+            Some(wanted) => status == wanted,
+            None => status != PollStatus::Archived,
+        })
+        .map(|status| match args.creator {
+            Some(creator) => CREATOR_POLL_COUNTS.with(|counts| {
+                counts.borrow().get(&(PrincipalKey(creator), status as u8))
+            }),
+            None => POLL_COUNTS.with(|counts| counts.borrow().get(&(status as u8))),
+        })
+        .map(|count| count.unwrap_or(0))
+        .sum()
+}
+
+// Every piece of state lives in stable structures, so nothing has to be
+// serialized in `pre_upgrade`; only heap-side timers, indexes introduced after
+// the data was written and maps whose key encoding changed need attention.
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    rekey_principal_maps();
+    rebuild_indexes();
+    scheduler::arm();
+}
+
+/// Moves the maps keyed by principals out of the memories they were written
+/// to under the bincode key encoding, a `u64` length and the bytes, into
+/// their current memories. Later upgrades find the old maps empty.
+fn rekey_principal_maps() {
+    let memory = |id| MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)));
+    VOTES.with(|votes| {
+        rekey(memory(1), &mut votes.borrow_mut(), |(poll_id, LegacyPrincipalKey(voter))| {
+            (poll_id, PrincipalKey(voter))
+        });
+    });
+    for (id, index) in [(4, &CREATOR_POLLS), (5, &VOTER_POLLS)] {
+        index.with(|index| {
+            rekey(memory(id), &mut index.borrow_mut(), |(LegacyPrincipalKey(principal), poll_id)| {
+                (PrincipalKey(principal), poll_id)
+            });
+        });
+    }
+}
+
+fn rebuild_indexes() {
+    // Polls created before tallies were kept have none; their ballots are
+    // counted once here. They predate the scheduler too.
+    let untallied: Vec<Poll> = POLLS.with(|polls| {
+        TALLIES.with(|tallies| {
+            let tallies = tallies.borrow();
+            polls.borrow()
+                .iter()
+                .filter(|(id, _)| !tallies.contains_key(id))
+                .filter_map(|(_, poll)| poll.decode().ok())
+                .collect()
+        })
+    });
+    for poll in untallied {
+        backfill_tally(&poll);
+        scheduler::schedule(&poll);
+        CREATOR_POLLS.with(|index| {
+            index.borrow_mut().insert((PrincipalKey(poll.creator), poll.id), ());
+        });
+    }
+
+    // Summaries indexed before the listing counters existed are counted once;
+    // polls indexed below count themselves.
+    if POLL_COUNTS.with(|counts| counts.borrow().is_empty()) {
+        let summaries: Vec<_> = POLL_SUMMARIES.with(|summaries| summaries.borrow().iter().collect());
+        for (id, summary) in summaries {
+            count_poll(id, &summary, true);
+        }
+    }
+
+    // Polls that hide their results were ordered by their vote counts before
+    // they were sealed; they move to their neutral place once.
+    if SEALED_POLLS.with(|polls| polls.borrow().is_empty()) {
+        let sealed: Vec<u64> = POLLS.with(|polls| {
+            polls.borrow()
+                .iter()
+                .filter_map(|(id, poll)| poll.decode().ok().map(|poll| (id, poll.results_visibility)))
+                .filter(|&(_, visibility)| visibility != ResultsVisibility::Always)
+                .map(|(id, _)| id)
+                .collect()
+        });
+        for id in sealed {
+            let summary = POLL_SUMMARIES.with(|summaries| summaries.borrow().get(&id));
+            if summary.is_some() {
+                unindex_poll(id);
+            }
+            SEALED_POLLS.with(|polls| polls.borrow_mut().insert(id, ()));
+            if let Some(summary) = summary {
+                index_poll(id, summary);
+            }
+        }
+    }
+
+    // Polls created before the listing indexes existed have no summary yet.
+    let unindexed: Vec<Poll> = POLLS.with(|polls| {
+        POLL_SUMMARIES.with(|summaries| {
+            let summaries = summaries.borrow();
+            polls.borrow()
+                .iter()
+                .filter(|(id, _)| !summaries.contains_key(id))
+                .filter_map(|(_, poll)| poll.decode().ok())
+                .collect()
+        })
+    });
+
+    for poll in unindexed {
+        let votes = TALLIES.with(|tallies| tallies.borrow().get(&poll.id))
+            .map_or(0, |tally| tally.unique_voters);
+        index_poll(poll.id, PollSummary::new(&poll, votes));
+    }
+}
+
+/// Counts the stored ballots of a poll into a new tally, indexing each under
+/// its voter.
+fn backfill_tally(poll: &Poll) {
+    let width = poll.voting_mode.tally_width(poll.options.len());
+    let mut tally = Tally {
+        // Secret ballots are final only once their reveal window ends.
+        finalized_at: poll.closed_at.filter(|_| poll.reveal_window.is_none()),
+        ..Tally::default()
+    };
+    VOTES.with(|votes| {
+        VOTER_POLLS.with(|index| {
+            let mut index = index.borrow_mut();
+            for ((_, voter), vote) in votes.borrow().range(poll_votes_range(poll.id, None)) {
+                index.insert((voter, poll.id), ());
+                if let Ok(vote) = vote.decode() {
+                    tally.record(width, None, &poll.voting_mode.counted(&vote));
+                }
+            }
+        });
+    });
+
+    let voters = tally.unique_voters;
+    TALLIES.with(|tallies| tallies.borrow_mut().insert(poll.id, tally));
+    update_summary(poll.id, |summary| summary.votes = voters);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `check` as the canister would after an upgrade: on a fresh heap
+    /// whose only state is a copy of the current stable memory.
+    pub(crate) fn after_upgrade(check: impl FnOnce() + Send + 'static) {
+        let snapshot = STABLE_MEMORY.with(|memory| memory.borrow().clone());
+        std::thread::spawn(move || {
+            STABLE_MEMORY.with(|memory| *memory.borrow_mut() = snapshot);
+            rekey_principal_maps();
+            rebuild_indexes();
+            check();
+        })
+        .join()
+        .expect("post-upgrade checks failed");
+    }
+
+    pub(crate) fn open_poll(id: u64, vote_change: VoteChangePolicy) -> Poll {
+        Poll {
+            id,
+            title: format!("Poll {}", id),
+            description: String::new(),
+            options: vec!["A".to_string(), "B".to_string(), "C".to_string()],
+            creator: Principal::from_slice(&[100]),
+            created_at: 1,
+            start_time: None,
+            deadline: None,
+            status: PollStatus::Open,
+            closed_at: None,
+            rewards: None,
+            voting_mode: VotingMode::SingleChoice,
+            seats: 1,
+            free_text: None,
+            questions: Vec::new(),
+            vote_change,
+            reveal_window: None,
+            ballot_visibility: BallotVisibility::Public,
+            results_visibility: ResultsVisibility::Always,
+            private: false,
+            eligibility: None,
+            token_gate: None,
+        }
+    }
+
+    pub(crate) fn ballot(poll_id: u64, choice: u32) -> VoteArgs {
+        VoteArgs { poll_id, choices: vec![choice], votes: None, value: None, answer: None, proof: None }
+    }
+
+    pub(crate) fn tally(poll_id: u64) -> (Vec<u64>, u64) {
+        let tally = TALLIES.with(|tallies| tallies.borrow().get(&poll_id)).unwrap_or_default();
+        (tally.counts, tally.unique_voters)
+    }
+
+    /// Runs a future that never waits, as calls answered by the mock ledger do.
+    pub(crate) fn resolve<T>(future: impl std::future::Future<Output = T>) -> T {
+        let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+        match std::pin::pin!(future).poll(&mut context) {
+            std::task::Poll::Ready(output) => output,
+            std::task::Poll::Pending => panic!("the future waited on a call"),
+        }
+    }
+
+    #[test]
+    fn changes_and_revocations_keep_tallies_consistent() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        insert_poll(open_poll(1, VoteChangePolicy::Revocable), 0);
+
+        cast_vote(ballot(1, 0), alice, 10, None, None, false).unwrap();
+        cast_vote(ballot(1, 0), bob, 10, None, None, false).unwrap();
+        assert_eq!(cast_vote(ballot(1, 1), alice, 11, None, None, false), Err(PollError::AlreadyVoted));
+
+        cast_vote(ballot(1, 2), alice, 11, None, None, true).unwrap();
+        assert_eq!(load_vote(1, alice).unwrap().map(|vote| vote.revision), Some(1));
+        assert_eq!(tally(1), (vec![1, 0, 1], 2));
+
+        withdraw_vote(1, bob, 12).unwrap();
+        assert_eq!(withdraw_vote(1, bob, 13), Err(PollError::NotVoted));
+        assert_eq!(cast_vote(ballot(1, 1), bob, 13, None, None, true), Err(PollError::NotVoted));
+        assert_eq!(tally(1), (vec![0, 0, 1], 1));
+
+        insert_poll(open_poll(2, VoteChangePolicy::Immutable), 0);
+        cast_vote(ballot(2, 1), alice, 10, None, None, false).unwrap();
+        let change = cast_vote(ballot(2, 0), alice, 11, None, None, true);
+        assert_eq!(change, Err(PollError::VoteChangeNotAllowed));
+        assert_eq!(withdraw_vote(2, alice, 11), Err(PollError::VoteChangeNotAllowed));
+        assert_eq!(tally(2), (vec![0, 1, 0], 1));
+    }
+
+    #[test]
+    fn drafts_publish_and_polls_move_through_their_lifecycle() {
+        let creator = Principal::from_slice(&[100]);
+        let alice = Principal::from_slice(&[1]);
+        let draft = |id, start_time, deadline| {
+            let mut poll = open_poll(id, VoteChangePolicy::Changeable);
+            poll.status = PollStatus::Draft;
+            poll.start_time = start_time;
+            poll.deadline = deadline;
+            insert_poll(poll, 0);
+        };
+        draft(4, None, Some(100));
+
+        let poll = find_poll(4).unwrap();
+        assert!(poll.is_visible_to(creator) && !poll.is_visible_to(alice));
+        assert_eq!(cast_vote(ballot(4, 0), alice, 10, None, None, false), Err(PollError::PollNotFound));
+        let not_open = Err(PollError::PollNotOpen { status: PollStatus::Draft });
+        assert_eq!(cast_vote(ballot(4, 0), creator, 10, None, None, false), not_open);
+        assert!(SCHEDULE.with(|schedule| schedule.borrow().is_empty()));
+
+        publish(4, creator, 10).unwrap();
+        let open = PollStatus::Open;
+        assert_eq!(publish(4, creator, 11), Err(PollError::InvalidTransition { from: open, to: open }));
+        assert_eq!(SCHEDULE.with(|schedule| schedule.borrow().get(&(100, 4))), Some(PollEvent::Close));
+        cast_vote(ballot(4, 1), alice, 11, None, None, false).unwrap();
+
+        let archive = |id, now| {
+            let from = [PollStatus::Closed, PollStatus::Cancelled];
+            transition_poll(id, &from, PollStatus::Archived, creator, now)
+        };
+        let cancel = |id, now| {
+            let from = [PollStatus::Draft, PollStatus::Scheduled, PollStatus::Open];
+            transition_poll(id, &from, PollStatus::Cancelled, creator, now)
+        };
+        let archived = PollStatus::Archived;
+        assert_eq!(archive(4, 12), Err(PollError::InvalidTransition { from: open, to: archived }));
+        transition_poll(4, &[PollStatus::Open], PollStatus::Closed, creator, 12).unwrap();
+        let closed = find_poll(4).unwrap();
+        assert_eq!((closed.status, closed.closed_at), (PollStatus::Closed, Some(12)));
+        assert_eq!(TALLIES.with(|tallies| tallies.borrow().get(&4)).unwrap().finalized_at, Some(12));
+        let late = cast_vote(ballot(4, 0), creator, 13, None, None, false);
+        assert_eq!(late, Err(PollError::PollClosed { closed_at: 12 }));
+        let cancelled = PollStatus::Cancelled;
+        let from = PollStatus::Closed;
+        assert_eq!(cancel(4, 13), Err(PollError::InvalidTransition { from, to: cancelled }));
+        archive(4, 13).unwrap();
+        assert_eq!(POLL_SUMMARIES.with(|summaries| summaries.borrow().get(&4)).unwrap().status, archived);
+
+        // A draft outlived by its deadline can only be cancelled; one whose
+        // start is still ahead is published as scheduled.
+        draft(5, None, Some(50));
+        assert_eq!(publish(5, creator, 60), Err(PollError::DeadlineInPast));
+        cancel(5, 60).unwrap();
+        assert_eq!(find_poll(5).unwrap().status, cancelled);
+
+        draft(6, Some(30), Some(100));
+        publish(6, creator, 20).unwrap();
+        let scheduled = find_poll(6).unwrap();
+        assert_eq!(scheduled.status, PollStatus::Scheduled);
+        assert_eq!(scheduled.status_at(30), PollStatus::Open);
+        assert_eq!(SCHEDULE.with(|schedule| schedule.borrow().get(&(30, 6))), Some(PollEvent::Open));
+    }
+
+    #[test]
+    fn upgrades_count_ballots_cast_before_tallies() {
+        let mut closed = open_poll(8, VoteChangePolicy::Changeable);
+        closed.status = PollStatus::Closed;
+        closed.closed_at = Some(20);
+        insert_poll(open_poll(7, VoteChangePolicy::Changeable), 0);
+        insert_poll(closed, 0);
+        insert_poll(open_poll(9, VoteChangePolicy::Changeable), 0);
+        for (poll_id, voter, choice) in [(7, 1, 0), (7, 2, 2), (7, 3, 2), (8, 1, 0)] {
+            store_vote(&Vote {
+                poll_id,
+                voter: Principal::from_slice(&[voter]),
+                choices: vec![choice],
+                votes: Vec::new(),
+                value: None,
+                voted_at: 5,
+                revision: 0,
+                weight: 1,
+            });
+        }
+        // Poll 9 was tallied as usual.
+        cast_vote(ballot(9, 1), Principal::from_slice(&[1]), 10, None, None, false).unwrap();
+        // Neither were the creator and voter indexes kept.
+        let creator = Principal::from_slice(&[100]);
+        CREATOR_POLLS.with(|index| index.borrow_mut().remove(&(PrincipalKey(creator), 7)));
+
+        after_upgrade(move || {
+            assert_eq!(tally(7), (vec![1, 0, 2], 3));
+            assert_eq!(tally(8), (vec![1, 0, 0], 1));
+            assert_eq!(tally(9), (vec![0, 1, 0], 1));
+            let finalized_at = |id| TALLIES.with(|tallies| tallies.borrow().get(&id)).unwrap().finalized_at;
+            assert_eq!((finalized_at(7), finalized_at(8)), (None, Some(20)));
+            let summary = |id| POLL_SUMMARIES.with(|summaries| summaries.borrow().get(&id)).unwrap();
+            assert_eq!((summary(7).votes, summary(8).votes), (3, 1));
+            let created = my_polls(creator, None, 10, 11).polls;
+            assert_eq!(created.iter().map(|poll| poll.id).collect::<Vec<_>>(), vec![7, 8, 9]);
+            let voted = my_votes(Principal::from_slice(&[1]), None, 10, 11).votes;
+            assert_eq!(voted.iter().map(|mine| mine.poll.id).collect::<Vec<_>>(), vec![7, 8, 9]);
+
+            // Ballots cast after the upgrade add to the backfilled counts.
+            cast_vote(ballot(7, 1), Principal::from_slice(&[4]), 11, None, None, false).unwrap();
+            assert_eq!(tally(7), (vec![1, 1, 2], 4));
+        });
+    }
+
+    #[test]
+    fn unversioned_polls_and_votes_still_decode() {
+        // Stored under the same bounds as before records were versioned.
+        assert_eq!(<Envelope<Poll>>::MAX_SIZE, 1024 * 1024);
+        assert_eq!(<Envelope<Vote>>::MAX_SIZE, 1024);
+
+        let creator = Principal::from_slice(&[100]);
+        let options = vec!["A".to_string(), "B".to_string(), "C".to_string()];
+        for (id, deadline, is_active) in [(5u64, None::<u64>, true), (6, Some(50), false)] {
+            let title = format!("Poll {}", id);
+            let legacy = (id, title, "Old".to_string(), options.clone(), creator, 1u64, deadline, is_active);
+            let bytes = bincode::serialize(&legacy).unwrap();
+            POLLS.with(|polls| polls.borrow_mut().insert(id, Envelope::from_bytes(Cow::Owned(bytes))));
+        }
+        for (voter, option_index) in [(1u8, 0u32), (2, 2)] {
+            let voter = Principal::from_slice(&[voter]);
+            let bytes = bincode::serialize(&(5u64, voter, option_index, 7u64)).unwrap();
+            let vote = Envelope::from_bytes(Cow::Owned(bytes));
+            VOTES.with(|votes| votes.borrow_mut().insert((5, PrincipalKey(voter)), vote));
+        }
+
+        // Detected by their layout: current polls, and unversioned ones that
+        // fail to decode, are not mistaken for one another.
+        let current = Envelope::new(&open_poll(5, VoteChangePolicy::Immutable));
+        assert!(unversioned_poll_len(&current.to_bytes()).is_none());
+        let legacy = (9u64, "Poll 9", "Old", options.clone(), creator, 1u64, None::<u64>, true);
+        let mut bytes = bincode::serialize(&legacy).unwrap();
+        bytes[16] = 0xff;
+        let broken = Envelope::<Poll>::from_bytes(Cow::Owned(bytes)).decode();
+        assert!(matches!(broken, Err(PollError::DecodeFailed { version: 0, .. })));
+
+        let open = find_poll(5).unwrap();
+        assert_eq!((open.title.as_str(), open.description.as_str()), ("Poll 5", "Old"));
+        assert_eq!((open.creator, open.options.len(), open.status), (creator, 3, PollStatus::Open));
+        let closed = find_poll(6).unwrap();
+        assert_eq!((closed.deadline, closed.status), (Some(50), PollStatus::Closed));
+        let vote = load_vote(5, Principal::from_slice(&[2])).unwrap().unwrap();
+        assert_eq!((vote.choices, vote.voted_at, vote.weight), (vec![2], 7, 1));
+
+        after_upgrade(|| {
+            assert_eq!(tally(5), (vec![1, 0, 1], 2));
+            // Rewritten polls and ballots are enveloped and keep decoding.
+            cast_vote(ballot(5, 1), Principal::from_slice(&[3]), 10, None, None, false).unwrap();
+            assert_eq!(tally(5), (vec![1, 1, 1], 3));
+            assert_eq!(load_vote(5, Principal::from_slice(&[1])).unwrap().unwrap().choices, vec![0]);
+        });
+    }
+
+    #[test]
+    fn principal_keyed_maps_move_to_the_current_encoding_on_upgrade() {
+        let creator = Principal::from_slice(&[100]);
+        let voters = [Principal::from_slice(&[1]), Principal::from_slice(&[7; 21])];
+        insert_poll(open_poll(5, VoteChangePolicy::Changeable), 2);
+        CREATOR_POLLS.with(|index| index.borrow_mut().remove(&(PrincipalKey(creator), 5)));
+        // Tallied, so the indexes are not rebuilt from the ballots.
+        let counted = Tally { counts: vec![1, 1, 0], unique_voters: 2, finalized_at: None };
+        TALLIES.with(|tallies| tallies.borrow_mut().insert(5, counted));
+
+        // Written as the canister did before principal keys were re-encoded.
+        let memory = |id| MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)));
+        let mut votes: StableBTreeMap<(u64, LegacyPrincipalKey), Envelope<Vote>, Memory> =
+            StableBTreeMap::init(memory(1));
+        for (choice, voter) in voters.iter().enumerate() {
+            let bytes = bincode::serialize(&(5u64, *voter, choice as u32, 7u64)).unwrap();
+            votes.insert((5, LegacyPrincipalKey(*voter)), Envelope::from_bytes(Cow::Owned(bytes)));
+        }
+        type LegacyIndex = StableBTreeMap<(LegacyPrincipalKey, u64), (), Memory>;
+        let mut created = LegacyIndex::init(memory(4));
+        created.insert((LegacyPrincipalKey(creator), 5), ());
+        let mut voted = LegacyIndex::init(memory(5));
+        for voter in voters {
+            voted.insert((LegacyPrincipalKey(voter), 5), ());
+        }
+
+        after_upgrade(move || {
+            assert_eq!(load_vote(5, voters[1]).unwrap().unwrap().choices, vec![1]);
+            assert_eq!(VOTES.with(|votes| votes.borrow().len()), 2);
+            assert_eq!(my_polls(creator, None, 10, 10).polls.len(), 1);
+            for voter in voters {
+                assert_eq!(my_votes(voter, None, 10, 10).votes.len(), 1);
+            }
+            let again = cast_vote(ballot(5, 2), voters[0], 10, None, None, false);
+            assert_eq!(again, Err(PollError::AlreadyVoted));
+
+            // The old maps are left empty, so a later upgrade moves nothing twice.
+            let old: StableBTreeMap<(u64, LegacyPrincipalKey), Envelope<Vote>, Memory> =
+                StableBTreeMap::init(memory(1));
+            assert!(old.is_empty());
+            rekey_principal_maps();
+            assert_eq!(VOTES.with(|votes| votes.borrow().len()), 2);
+        });
+    }
+
+    #[test]
+    fn creator_and_voter_indexes_page_in_id_order() {
+        let creator = Principal::from_slice(&[100]);
+        let alice = Principal::from_slice(&[1]);
+        for id in [12, 10, 11] {
+            insert_poll(open_poll(id, VoteChangePolicy::Changeable), 0);
+        }
+        let mut other = open_poll(13, VoteChangePolicy::Changeable);
+        other.creator = alice;
+        insert_poll(other, 0);
+        cast_vote(ballot(12, 0), alice, 10, None, None, false).unwrap();
+        cast_vote(ballot(10, 2), alice, 10, None, None, false).unwrap();
+
+        let ids = |page: &PollsPage| page.polls.iter().map(|poll| poll.id).collect::<Vec<_>>();
+        let first = my_polls(creator, None, 2, 10);
+        assert_eq!((ids(&first), first.next_cursor), (vec![10, 11], Some(11)));
+        let second = my_polls(creator, first.next_cursor, 2, 10);
+        assert_eq!((ids(&second), second.next_cursor), (vec![12], None));
+        assert_eq!(ids(&my_polls(alice, None, 10, 10)), vec![13]);
+
+        let voted = my_votes(alice, None, 10, 10);
+        let ballots: Vec<_> = voted.votes.iter()
+            .map(|mine| (mine.poll.id, mine.vote.choices.clone()))
+            .collect();
+        assert_eq!(ballots, vec![(10, vec![2]), (12, vec![0])]);
+        assert!(my_votes(creator, None, 10, 10).votes.is_empty());
+
+        // Revoking a ballot drops the poll from the voter's index.
+        insert_poll(open_poll(14, VoteChangePolicy::Revocable), 0);
+        cast_vote(ballot(14, 1), alice, 10, None, None, false).unwrap();
+        assert_eq!(my_votes(alice, Some(12), 10, 10).votes.len(), 1);
+        withdraw_vote(14, alice, 11).unwrap();
+        assert!(my_votes(alice, Some(12), 10, 11).votes.is_empty());
+    }
+
+    #[test]
+    fn approval_ballots_count_every_selected_option() {
+        let mut poll = open_poll(1, VoteChangePolicy::Changeable);
+        poll.voting_mode = VotingMode::MultipleChoice { min_choices: 1, max_choices: 2 };
+        insert_poll(poll, 0);
+        let approve = |choices: Vec<u32>| VoteArgs { choices, ..ballot(1, 0) };
+        let (alice, bob) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+
+        cast_vote(approve(vec![0, 2]), alice, 10, None, None, false).unwrap();
+        cast_vote(approve(vec![2]), bob, 10, None, None, false).unwrap();
+        assert_eq!(tally(1), (vec![1, 0, 2], 2));
+        let too_many = cast_vote(approve(vec![0, 1, 2]), bob, 11, None, None, true);
+        assert_eq!(too_many, Err(PollError::InvalidChoiceCount { min: 1, max: 2 }));
+
+        // A changed ballot moves its approvals.
+        cast_vote(approve(vec![1, 0]), alice, 11, None, None, true).unwrap();
+        assert_eq!(tally(1), (vec![1, 1, 1], 2));
+    }
+
+    #[test]
+    fn failures_report_a_typed_error() {
+        let alice = Principal::from_slice(&[1]);
+        let creator = Principal::from_slice(&[100]);
+        insert_poll(open_poll(1, VoteChangePolicy::Immutable), 0);
+        let scheduled = open_poll(2, VoteChangePolicy::Immutable);
+        insert_poll(Poll { status: PollStatus::Scheduled, start_time: Some(50), ..scheduled }, 0);
+        insert_poll(Poll { deadline: Some(20), ..open_poll(3, VoteChangePolicy::Revocable) }, 0);
+
+        assert_eq!(cast_vote(ballot(9, 0), alice, 10, None, None, false), Err(PollError::PollNotFound));
+        let unknown = cast_vote(ballot(1, 3), alice, 10, None, None, false);
+        assert_eq!(unknown, Err(PollError::InvalidOption { max: 2 }));
+        let early = cast_vote(ballot(2, 0), alice, 10, None, None, false);
+        assert_eq!(early, Err(PollError::PollNotOpen { status: PollStatus::Scheduled }));
+        let late = cast_vote(ballot(3, 0), alice, 30, None, None, false);
+        assert_eq!(late, Err(PollError::PollClosed { closed_at: 20 }));
+
+        cast_vote(ballot(1, 0), alice, 10, None, None, false).unwrap();
+        let change = cast_vote(ballot(1, 1), alice, 11, None, None, true);
+        assert_eq!(change, Err(PollError::VoteChangeNotAllowed));
+        assert_eq!(withdraw_vote(3, alice, 10), Err(PollError::NotVoted));
+
+        let reopen = transition_poll(1, &[PollStatus::Closed], PollStatus::Open, creator, 10);
+        let open = PollStatus::Open;
+        assert_eq!(reopen, Err(PollError::InvalidTransition { from: open, to: open }));
+        let missing = transition_poll(9, &[PollStatus::Open], PollStatus::Closed, creator, 10);
+        assert_eq!(missing, Err(PollError::PollNotFound));
+    }
+
+    #[test]
+    fn ranked_counts_read_ballots_a_page_at_a_time() {
+        let mut poll = open_poll(1, VoteChangePolicy::Changeable);
+        poll.options.push("D".to_string());
+        poll.voting_mode = VotingMode::Ranked { method: RankingMethod::SingleTransferableVote };
+        poll.seats = 2;
+        insert_poll(poll.clone(), 0);
+        let rankings = [vec![0, 1], vec![0, 1], vec![0, 1], vec![2], vec![3, 2], vec![3, 2], vec![1]];
+        for (voter, choices) in rankings.iter().enumerate() {
+            let args = VoteArgs { choices: choices.clone(), ..ballot(1, 0) };
+            cast_vote(args, Principal::from_slice(&[voter as u8 + 1]), 10, None, None, false).unwrap();
+        }
+
+        // Seven ballots in pages of two: the fourth page is short and ends the count.
+        let pages: Vec<bool> = (0..4).map(|_| count_ranked_page(&poll, 2)).collect();
+        assert_eq!(pages, vec![false, false, false, true]);
+        assert!(count_ranked_page(&poll, 2));
+
+        let ballots = [(vec![0, 1], 3), (vec![1], 1), (vec![2], 1), (vec![3, 2], 2)];
+        let election = stv::count(1, 2, 4, &ballots);
+        let method = RankingMethod::SingleTransferableVote;
+        let expected = RankedResults::compute(method, 4, &ballots, Some(&election));
+        let results = RANKED_RESULTS.with(|results| results.borrow().get(&1)).unwrap().decode().unwrap();
+        assert_eq!((results.winners, results.borda_scores), (expected.winners, expected.borda_scores));
+        let report = ELECTION_REPORTS.with(|reports| reports.borrow().get(&1)).unwrap().decode().unwrap();
+        assert_eq!(report.elected, election.elected);
+    }
+
+    fn listing(status: Option<PollStatus>, creator: Option<Principal>, sort_by: PollSort) -> ListPollsArgs {
+        ListPollsArgs { status, creator, sort_by: Some(sort_by), cursor: None, limit: 2 }
+    }
+
+    fn list_ids(args: ListPollsArgs, caller: Principal, now: u64) -> Vec<u64> {
+        let mut args = args;
+        let mut ids = Vec::new();
+        loop {
+            let page = list(&args, caller, now);
+            ids.extend(page.polls.iter().map(|poll| poll.id));
+            match page.next_cursor {
+                Some(cursor) => args.cursor = Some(cursor),
+                None => return ids,
+            }
+        }
+    }
+
+    #[test]
+    fn listings_filter_sort_page_and_count() {
+        let creator = Principal::from_slice(&[100]);
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let polls = [(1, 10, Some(500), PollStatus::Open, 1), (2, 30, Some(300), PollStatus::Open, 0),
+            (3, 20, None, PollStatus::Closed, 2), (4, 40, Some(400), PollStatus::Archived, 0)];
+        for (id, created_at, deadline, status, votes) in polls {
+            let poll = Poll { created_at, deadline, status, ..open_poll(id, VoteChangePolicy::Immutable) };
+            insert_poll(poll, votes);
+        }
+        let mut by_alice = open_poll(5, VoteChangePolicy::Immutable);
+        by_alice.creator = alice;
+        by_alice.created_at = 50;
+        insert_poll(by_alice, 0);
+        insert_poll(Poll { status: PollStatus::Draft, ..open_poll(6, VoteChangePolicy::Immutable) }, 0);
+        insert_poll(Poll { private: true, ..open_poll(7, VoteChangePolicy::Immutable) }, 0);
+
+        // Drafts and private polls show only to their creator.
+        let newest = |caller| list_ids(listing(None, None, PollSort::CreatedAt), caller, 100);
+        assert_eq!(newest(bob), vec![5, 2, 3, 1]);
+        assert_eq!(newest(creator), vec![5, 2, 3, 1, 6, 7]);
+        let soonest = list_ids(listing(None, None, PollSort::Deadline), bob, 100);
+        assert_eq!(soonest, vec![2, 1, 3, 5]);
+        let busiest = list_ids(listing(None, None, PollSort::VoteCount), bob, 100);
+        assert_eq!(busiest, vec![3, 1, 2, 5]);
+
+        let archived = listing(Some(PollStatus::Archived), None, PollSort::CreatedAt);
+        assert_eq!(list_ids(archived, bob, 100), vec![4]);
+        let by_creator = listing(Some(PollStatus::Open), Some(creator), PollSort::CreatedAt);
+        assert_eq!(list_ids(by_creator, bob, 100), vec![2, 1]);
+
+        // Pages carry a cursor until the last one; the total ignores paging.
+        let first = list(&listing(None, None, PollSort::CreatedAt), bob, 100);
+        assert_eq!((first.polls.len(), first.total), (2, 4));
+        let cursor = first.next_cursor.unwrap();
+        assert_eq!((cursor.key, cursor.poll_id), (u64::MAX - 30, 2));
+        let creator_total = list(&listing(None, Some(creator), PollSort::Deadline), bob, 100).total;
+        assert_eq!(creator_total, 3);
+
+        // Totals follow stored transitions and deletions.
+        let closed = listing(Some(PollStatus::Closed), None, PollSort::CreatedAt);
+        assert_eq!(list(&closed, bob, 100).total, 1);
+        update_summary(1, |summary| summary.status = PollStatus::Closed);
+        assert_eq!(list(&closed, bob, 100).total, 2);
+        unindex_poll(1);
+        assert_eq!(list(&closed, bob, 100).total, 1);
+
+        // Counters rebuilt on upgrade match the ones kept since.
+        POLL_COUNTS.with(|counts| {
+            let statuses: Vec<u8> = counts.borrow().iter().map(|(status, _)| status).collect();
+            for status in statuses {
+                counts.borrow_mut().remove(&status);
+            }
+        });
+        after_upgrade(move || {
+            assert_eq!(list(&listing(None, None, PollSort::CreatedAt), bob, 100).total, 3);
+        });
+    }
+
+    #[test]
+    fn deleted_polls_are_purged_in_batches() {
+        let creator = Principal::from_slice(&[100]);
+        let voters: Vec<Principal> = (1..=5u8).map(|id| Principal::from_slice(&[id])).collect();
+        insert_poll(Poll { deadline: Some(50), ..open_poll(9, VoteChangePolicy::Changeable) }, 0);
+        for &voter in &voters {
+            cast_vote(ballot(9, 0), voter, 10, None, None, false).unwrap();
+        }
+        LEGACY_POLL_IDS.with(|ids| ids.borrow_mut().insert(77, 9));
+
+        assert_eq!(remove_poll(9, voters[0], 20), Err(PollError::NotAuthorized));
+        remove_poll(9, creator, 20).unwrap();
+        assert!(load_poll(9).unwrap().is_none());
+        assert!(TALLIES.with(|tallies| tallies.borrow().get(&9)).is_none());
+        assert!(LEGACY_POLL_IDS.with(|ids| ids.borrow().is_empty()));
+        let pending: Vec<_> = SCHEDULE.with(|schedule| schedule.borrow().iter().collect());
+        assert_eq!(pending, vec![((20, 9), PollEvent::Purge)]);
+
+        // Ballots go a batch at a time, with their voter index entries.
+        let left = || VOTES.with(|votes| votes.borrow().range(poll_votes_range(9, None)).count());
+        assert!(!purge_page(9, 2));
+        assert_eq!(left(), 3);
+        assert!(!purge_page(9, 3));
+        assert!(purge_page(9, 3));
+        assert_eq!(left(), 0);
+        assert!(my_votes(voters[4], None, 10, 30).votes.is_empty());
+        assert!(VOTER_POLLS.with(|index| index.borrow().is_empty()));
+    }
+
+    #[test]
+    fn hidden_results_keep_a_neutral_vote_count_order() {
+        let bob = Principal::from_slice(&[2]);
+        let polls = [(1, 1, ResultsVisibility::Always), (2, 5, ResultsVisibility::AfterClose),
+            (3, 3, ResultsVisibility::AfterVoting), (4, 0, ResultsVisibility::Always)];
+        for (id, votes, results_visibility) in polls {
+            insert_poll(Poll { results_visibility, ..open_poll(id, VoteChangePolicy::Immutable) }, votes);
+            let tally = Tally { unique_voters: votes, ..Tally::default() };
+            TALLIES.with(|tallies| tallies.borrow_mut().insert(id, tally));
+        }
+        let busiest = move || list_ids(listing(None, None, PollSort::VoteCount), bob, 100);
+        assert_eq!(busiest(), vec![1, 2, 3, 4]);
+        let first = list(&listing(None, None, PollSort::VoteCount), bob, 100);
+        assert_eq!(first.next_cursor.map(|cursor| cursor.key), Some(u64::MAX));
+
+        // Counts are ranked once the poll closes.
+        update_summary(2, |summary| summary.status = PollStatus::Closed);
+        assert_eq!(busiest(), vec![2, 1, 3, 4]);
+        update_summary(3, |summary| summary.votes = 4);
+        assert_eq!(busiest(), vec![2, 1, 3, 4]);
+
+        // Polls indexed by their counts before sealing move on upgrade.
+        unindex_poll(3);
+        SEALED_POLLS.with(|polls| polls.borrow_mut().remove(&3));
+        let summary = PollSummary::new(&open_poll(3, VoteChangePolicy::Immutable), 4);
+        index_poll(3, summary);
+        SEALED_POLLS.with(|polls| polls.borrow_mut().remove(&2));
+        assert_eq!(busiest(), vec![2, 3, 1, 4]);
+        after_upgrade(move || {
+            assert_eq!(busiest(), vec![2, 1, 3, 4]);
+            let total = list(&listing(None, None, PollSort::VoteCount), bob, 100).total;
+            assert_eq!(total, 4);
+        });
+    }
+
+    #[test]
+    fn sparse_listings_stop_at_the_scan_budget() {
+        let alice = Principal::from_slice(&[1]);
+        let polls = MAX_SCANNED_PER_PAGE as u64 + 5;
+        for id in 1..=polls {
+            let mut poll = open_poll(id, VoteChangePolicy::Immutable);
+            poll.created_at = id;
+            if id <= 2 {
+                poll.creator = alice;
+            }
+            insert_poll(poll, 0);
+        }
+
+        // Alice's polls are the oldest, so the first page runs out of budget.
+        let args = listing(None, Some(alice), PollSort::CreatedAt);
+        let first = list(&args, alice, 100);
+        assert!(first.polls.is_empty());
+        assert_eq!(first.total, 2);
+        let cursor = first.next_cursor.expect("a cut-short page carries a cursor");
+        assert_eq!(cursor.poll_id, polls - MAX_SCANNED_PER_PAGE as u64 + 1);
+        assert_eq!(list_ids(args, alice, 100), vec![2, 1]);
+    }
+
+    #[test]
+    fn gated_ballots_count_with_the_voters_weight() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let carol = Principal::from_slice(&[3]);
+        let voters = [(alice, 3), (bob, 1)];
+        let tree = pollrush_common::merkle::MerkleTree::new(&voters);
+        let mut poll = open_poll(3, VoteChangePolicy::Changeable);
+        poll.eligibility = Some(eligibility::eligibility(&tree, true));
+        poll.token_gate = Some(TokenGate { token: None, min_balance: 10 });
+        insert_poll(poll, 0);
+        let configure = |config: PollConfig| {
+            CONFIG.with(|cell| cell.borrow_mut().set(Envelope::new(&config)).unwrap());
+        };
+        configure(PollConfig { pps_token: Some(Principal::from_slice(&[50])), ..PollConfig::default() });
+
+        let proven = |choice, index| VoteArgs {
+            proof: Some(eligibility::proof(&tree, index, voters[index].1)),
+            ..ballot(3, choice)
+        };
+        // Balances are read from the PPS token as `vote` does.
+        let pps = Principal::from_slice(&[50]);
+        let cast = |args: VoteArgs, voter, replace| {
+            let balance = resolve(gated_balance(args.poll_id, voter))?;
+            cast_vote(args, voter, 10, None, balance, replace)
+        };
+        for (voter, balance) in [(alice, 10u64), (bob, 50), (carol, 10)] {
+            token::mock_ledger::set_balance(pps, voter, balance);
+        }
+        assert_eq!(cast(ballot(3, 0), alice, false), Err(PollError::NotEligible));
+        assert_eq!(cast(proven(0, 1), carol, false), Err(PollError::NotEligible));
+        let short = |available| Err(PollError::InsufficientBalance { available });
+        token::mock_ledger::set_balance(pps, alice, 9u64);
+        assert_eq!(cast(proven(0, 0), alice, false), short(9));
+        assert_eq!(cast_vote(proven(0, 0), alice, 10, None, None, false), short(0));
+
+        token::mock_ledger::set_balance(pps, alice, 10u64);
+        cast(proven(0, 0), alice, false).unwrap();
+        cast(proven(1, 1), bob, false).unwrap();
+        assert_eq!(tally(3), (vec![3, 1, 0], 2));
+        cast(proven(2, 0), alice, true).unwrap();
+        assert_eq!(tally(3), (vec![0, 1, 3], 2));
+
+        // Ledgers off the allowlist fail the checks made before any call.
+        let ledger = Principal::from_slice(&[60]);
+        insert_poll(Poll {
+            token_gate: Some(TokenGate { token: Some(ledger), min_balance: 10 }),
+            ..open_poll(4, VoteChangePolicy::Changeable)
+        }, 0);
+        let checked = || check_ballot(&ballot(4, 0), alice, 10, None, false).map(|_| ());
+        assert!(matches!(checked(), Err(PollError::InvalidConfig { .. })));
+        configure(PollConfig { allowed_ledgers: vec![ledger], ..PollConfig::default() });
+        assert_eq!(checked(), Ok(()));
+        token::mock_ledger::set_balance(ledger, alice, 10u64);
+        cast(ballot(4, 1), alice, false).unwrap();
+        assert_eq!(tally(4), (vec![0, 1, 0], 1));
+        assert_eq!(cast(ballot(4, 1), bob, false), short(0));
+        token::mock_ledger::stop(ledger);
+        assert!(matches!(cast(ballot(4, 1), bob, false), Err(PollError::CanisterCallFailed { .. })));
+    }
 }
 
 // Export Candid interface
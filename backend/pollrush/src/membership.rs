@@ -0,0 +1,301 @@
+//! Private polls: who may see and vote in them.
+//!
+//! A private poll is visible only to its creator and its members. The creator
+//! manages the member allowlist directly, or hands out invite codes that add
+//! whoever redeems them, either once or up to a number of times.
+
+use super::*;
+use std::ops::RangeInclusive;
+
+const INVITE_CODE_BYTES: usize = 16;
+
+/// Most principals `add_members` and `remove_members` take in one call.
+const MAX_MEMBER_BATCH: u32 = 500;
+
+pub(crate) type InviteCode = [u8; INVITE_CODE_BYTES];
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub(crate) struct Invite {
+    created_at: u64,
+    /// Redemptions allowed; unlimited when `None`.
+    max_uses: Option<u32>,
+    uses: u32,
+    expires_at: Option<u64>,
+}
+
+impl Versioned for Invite {
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 64;
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct CreateInviteArgs {
+    poll_id: u64,
+    /// 1 for a one-time code; unlimited when omitted.
+    max_uses: Option<u32>,
+    expires_at: Option<u64>,
+}
+
+/// An invite as listed to the poll's managers.
+#[derive(CandidType, Deserialize)]
+pub(crate) struct InviteInfo {
+    code: String,
+    created_at: u64,
+    max_uses: Option<u32>,
+    uses: u32,
+    expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct MembersPage {
+    members: Vec<Principal>,
+    next_cursor: Option<Principal>,
+}
+
+fn encode_code(code: &InviteCode) -> String {
+    code.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_code(code: &str) -> Option<InviteCode> {
+    if code.len() != 2 * INVITE_CODE_BYTES || !code.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; INVITE_CODE_BYTES];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&code[2 * index..2 * index + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Key bounds covering every invite to `poll_id`.
+fn invites_range(poll_id: u64) -> RangeInclusive<(u64, InviteCode)> {
+    (poll_id, [0; INVITE_CODE_BYTES])..=(poll_id, [u8::MAX; INVITE_CODE_BYTES])
+}
+
+pub(crate) fn is_member(poll_id: u64, principal: Principal) -> bool {
+    POLL_MEMBERS.with(|members| members.borrow().contains_key(&(poll_id, PrincipalKey(principal))))
+}
+
+/// Loads a private poll the caller may manage.
+fn managed_private_poll(poll_id: u64, caller: Principal) -> Result<Poll, PollError> {
+    let poll = find_poll(poll_id)?;
+    ensure_can_manage(&poll, caller)?;
+    if !poll.private {
+        return Err(PollError::PollNotPrivate);
+    }
+    Ok(poll)
+}
+
+fn check_batch(principals: &[Principal]) -> Result<(), PollError> {
+    if principals.len() > MAX_MEMBER_BATCH as usize {
+        return Err(PollError::TooManyMembers { max: MAX_MEMBER_BATCH });
+    }
+    Ok(())
+}
+
+pub(crate) fn add_members(
+    poll_id: u64,
+    principals: Vec<Principal>,
+    caller: Principal,
+) -> Result<(), PollError> {
+    check_batch(&principals)?;
+    let poll = managed_private_poll(poll_id, caller)?;
+    POLL_MEMBERS.with(|members| {
+        let mut members = members.borrow_mut();
+        for principal in principals {
+            members.insert((poll.id, PrincipalKey(principal)), ());
+        }
+    });
+    Ok(())
+}
+
+/// Removes principals from the allowlist. Ballots they already cast stay
+/// counted.
+pub(crate) fn remove_members(
+    poll_id: u64,
+    principals: Vec<Principal>,
+    caller: Principal,
+) -> Result<(), PollError> {
+    check_batch(&principals)?;
+    let poll = managed_private_poll(poll_id, caller)?;
+    POLL_MEMBERS.with(|members| {
+        let mut members = members.borrow_mut();
+        for principal in principals {
+            members.remove(&(poll.id, PrincipalKey(principal)));
+        }
+    });
+    Ok(())
+}
+
+pub(crate) fn members(
+    poll_id: u64,
+    cursor: Option<Principal>,
+    limit: u32,
+    caller: Principal,
+) -> Result<MembersPage, PollError> {
+    let poll = managed_private_poll(poll_id, caller)?;
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+
+    let mut page: Vec<Principal> = POLL_MEMBERS.with(|members| {
+        members.borrow()
+            .range(poll_votes_range(poll.id, cursor))
+            .take(limit + 1)
+            .map(|((_, member), _)| member.0)
+            .collect()
+    });
+    let next_cursor = if page.len() > limit {
+        page.truncate(limit);
+        page.last().copied()
+    } else {
+        None
+    };
+    Ok(MembersPage { members: page, next_cursor })
+}
+
+/// Checks that the caller may create the invite `args` describe, returning
+/// its poll.
+pub(crate) fn check_invite(args: &CreateInviteArgs, caller: Principal, now: u64) -> Result<Poll, PollError> {
+    let poll = managed_private_poll(args.poll_id, caller)?;
+    if args.max_uses == Some(0) {
+        return Err(PollError::InvalidConfig { reason: "max_uses must be positive".to_string() });
+    }
+    if args.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(PollError::InvalidConfig { reason: "expires_at must be in the future".to_string() });
+    }
+    Ok(poll)
+}
+
+/// Creates an invite from `random` bytes and returns its code.
+pub(crate) fn create_invite(
+    args: CreateInviteArgs,
+    random: InviteCode,
+    caller: Principal,
+    now: u64,
+) -> Result<String, PollError> {
+    let poll = check_invite(&args, caller, now)?;
+
+    let invite = Invite {
+        created_at: now,
+        max_uses: args.max_uses,
+        uses: 0,
+        expires_at: args.expires_at,
+    };
+    INVITES.with(|invites| invites.borrow_mut().insert((poll.id, random), Envelope::new(&invite)));
+    Ok(encode_code(&random))
+}
+
+pub(crate) fn revoke_invite(poll_id: u64, code: &str, caller: Principal) -> Result<(), PollError> {
+    let poll = managed_private_poll(poll_id, caller)?;
+    let code = decode_code(code).ok_or(PollError::InviteNotFound)?;
+    INVITES.with(|invites| invites.borrow_mut().remove(&(poll.id, code)))
+        .map(|_| ())
+        .ok_or(PollError::InviteNotFound)
+}
+
+pub(crate) fn invites(poll_id: u64, caller: Principal) -> Result<Vec<InviteInfo>, PollError> {
+    let poll = managed_private_poll(poll_id, caller)?;
+    INVITES.with(|invites| {
+        invites.borrow()
+            .range(invites_range(poll.id))
+            .map(|((_, code), invite)| {
+                let invite = invite.decode()?;
+                Ok(InviteInfo {
+                    code: encode_code(&code),
+                    created_at: invite.created_at,
+                    max_uses: invite.max_uses,
+                    uses: invite.uses,
+                    expires_at: invite.expires_at,
+                })
+            })
+            .collect()
+    })
+}
+
+/// Makes the caller a member of the poll the invite is for. Redeeming an
+/// invite to a poll the caller can already see uses none of its redemptions.
+pub(crate) fn redeem(poll_id: u64, code: &str, caller: Principal, now: u64) -> Result<(), PollError> {
+    let code = decode_code(code).ok_or(PollError::InviteNotFound)?;
+    let key = (poll_id, code);
+    let mut invite = INVITES.with(|invites| invites.borrow().get(&key))
+        .ok_or(PollError::InviteNotFound)?
+        .decode()?;
+    if find_poll(poll_id)?.is_visible_to(caller) {
+        return Ok(());
+    }
+    if let Some(expired_at) = invite.expires_at.filter(|&expires_at| now >= expires_at) {
+        return Err(PollError::InviteExpired { expired_at });
+    }
+    if invite.max_uses.is_some_and(|max_uses| invite.uses >= max_uses) {
+        return Err(PollError::InviteUsedUp);
+    }
+
+    invite.uses += 1;
+    INVITES.with(|invites| invites.borrow_mut().insert(key, Envelope::new(&invite)));
+    POLL_MEMBERS.with(|members| members.borrow_mut().insert((poll_id, PrincipalKey(caller)), ()));
+    Ok(())
+}
+
+/// Drops up to `budget` of the members and invites of a deleted poll.
+pub(crate) fn purge(poll_id: u64, budget: &mut usize) {
+    let members = poll_votes_range(poll_id, None);
+    POLL_MEMBERS.with(|map| drain_range(&mut map.borrow_mut(), members, budget));
+    INVITES.with(|invites| drain_range(&mut invites.borrow_mut(), invites_range(poll_id), budget));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invites_admit_members_until_used_up() {
+        let creator = Principal::from_slice(&[100]);
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let carol = Principal::from_slice(&[3]);
+        let mut poll = crate::tests::open_poll(40, VoteChangePolicy::Changeable);
+        poll.private = true;
+        insert_poll(poll, 0);
+
+        let invite = |max_uses, expires_at, random| {
+            let args = CreateInviteArgs { poll_id: 40, max_uses, expires_at };
+            create_invite(args, random, creator, 10)
+        };
+        let once = invite(Some(1), None, [1; INVITE_CODE_BYTES]).unwrap();
+        let expiring = invite(None, Some(20), [2; INVITE_CODE_BYTES]).unwrap();
+        assert_eq!(decode_code(&once), Some([1; INVITE_CODE_BYTES]));
+
+        let poll = find_poll(40).unwrap();
+        assert!(poll.is_visible_to(creator) && !poll.is_visible_to(alice));
+        redeem(40, &once, alice, 11).unwrap();
+        redeem(40, &once, alice, 12).unwrap();
+        assert!(poll.is_visible_to(alice));
+        assert_eq!(redeem(40, &once, bob, 12), Err(PollError::InviteUsedUp));
+        assert_eq!(redeem(40, &expiring, bob, 20), Err(PollError::InviteExpired { expired_at: 20 }));
+        assert_eq!(redeem(41, &once, bob, 12), Err(PollError::InviteNotFound));
+        assert_eq!(redeem(40, "not a code", bob, 12), Err(PollError::InviteNotFound));
+
+        add_members(40, vec![bob, carol], creator).unwrap();
+        remove_members(40, vec![carol], creator).unwrap();
+        assert!(poll.is_visible_to(bob) && !poll.is_visible_to(carol));
+
+        let mut budget = usize::MAX;
+        purge(40, &mut budget);
+        assert!(!poll.is_visible_to(alice) && !poll.is_visible_to(bob));
+    }
+
+    #[test]
+    fn only_managers_of_private_polls_create_invites() {
+        let creator = Principal::from_slice(&[100]);
+        let alice = Principal::from_slice(&[1]);
+        insert_poll(Poll { private: true, ..crate::tests::open_poll(42, VoteChangePolicy::Changeable) }, 0);
+        insert_poll(crate::tests::open_poll(43, VoteChangePolicy::Changeable), 0);
+
+        let args = |poll_id, max_uses| CreateInviteArgs { poll_id, max_uses, expires_at: None };
+        assert!(check_invite(&args(42, None), creator, 10).is_ok());
+        assert!(matches!(check_invite(&args(42, None), alice, 10), Err(PollError::NotAuthorized)));
+        assert!(matches!(check_invite(&args(43, None), creator, 10), Err(PollError::PollNotPrivate)));
+        assert!(matches!(check_invite(&args(44, None), creator, 10), Err(PollError::PollNotFound)));
+        let unusable = check_invite(&args(42, Some(0)), creator, 10);
+        assert!(matches!(unusable, Err(PollError::InvalidConfig { .. })));
+    }
+}
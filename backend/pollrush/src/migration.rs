@@ -0,0 +1,295 @@
+//! Import of polls from the legacy heap-based canister (`backend/src/lib.rs`),
+//! whose `thread_local` state is wiped by every upgrade.
+//!
+//! Before upgrading a legacy deployment, export its polls with the legacy
+//! `get_polls` query and feed them to `import_legacy_polls` in batches.
+//! Importing the same legacy poll twice returns its existing id.
+
+use super::*;
+use std::collections::HashMap;
+
+/// A poll as returned by the legacy canister's `get_polls`.
+#[derive(CandidType, Deserialize, Clone)]
+pub(crate) struct LegacyPoll {
+    id: u64,
+    creator: String,
+    title: String,
+    options: Vec<String>,
+    rewards: Option<RewardInfo>,
+    deadline: u64,
+    votes: Vec<u64>,
+    voters: HashMap<String, usize>,
+    created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq)]
+pub(crate) struct ImportedPoll {
+    legacy_id: u64,
+    poll_id: u64,
+}
+
+/// A legacy poll checked and converted, ready to be written.
+struct ConvertedPoll {
+    legacy_id: u64,
+    poll: Poll,
+    ballots: Vec<(Principal, u32)>,
+}
+
+/// Imports legacy polls with their voters and rewards; controllers only.
+#[ic_cdk::update]
+fn import_legacy_polls(polls: Vec<LegacyPoll>) -> Result<Vec<ImportedPoll>, PollError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(PollError::NotAuthorized);
+    }
+
+    let imported = import_polls(polls, time())?;
+    scheduler::arm();
+    Ok(imported)
+}
+
+/// Validates the whole batch before writing anything, so a malformed record
+/// leaves no partial import behind.
+pub(crate) fn import_polls(polls: Vec<LegacyPoll>, now: u64) -> Result<Vec<ImportedPoll>, PollError> {
+    let mut imported = Vec::with_capacity(polls.len());
+    let mut pending = Vec::new();
+    let mut seen = std::collections::BTreeSet::new();
+
+    for legacy in polls {
+        if !seen.insert(legacy.id) {
+            return Err(PollError::InvalidLegacyPoll {
+                legacy_id: legacy.id,
+                reason: "listed more than once in the batch".to_string(),
+            });
+        }
+        match LEGACY_POLL_IDS.with(|ids| ids.borrow().get(&legacy.id)) {
+            Some(poll_id) => imported.push(ImportedPoll { legacy_id: legacy.id, poll_id }),
+            None => pending.push(convert(legacy, now)?),
+        }
+    }
+
+    for converted in pending {
+        imported.push(ImportedPoll {
+            legacy_id: converted.legacy_id,
+            poll_id: write(converted),
+        });
+    }
+
+    Ok(imported)
+}
+
+fn convert(legacy: LegacyPoll, now: u64) -> Result<ConvertedPoll, PollError> {
+    let invalid = |reason: String| PollError::InvalidLegacyPoll { legacy_id: legacy.id, reason };
+
+    let creator = Principal::from_text(&legacy.creator)
+        .map_err(|err| invalid(format!("creator {}: {}", legacy.creator, err)))?;
+
+    let mut ballots = Vec::with_capacity(legacy.voters.len());
+    for (voter, option_index) in &legacy.voters {
+        let voter = Principal::from_text(voter)
+            .map_err(|err| invalid(format!("voter {}: {}", voter, err)))?;
+        if *option_index >= legacy.options.len() {
+            return Err(invalid(format!("option {} out of range", option_index)));
+        }
+        ballots.push((voter, *option_index as u32));
+    }
+
+    // The legacy canister kept the counts alongside the voters; both must agree.
+    let mut counts = vec![0u64; legacy.options.len()];
+    for &(_, option_index) in &ballots {
+        counts[option_index as usize] += 1;
+    }
+    if legacy.votes != counts {
+        let reason = format!("votes {:?} do not match the voters' choices {:?}", legacy.votes, counts);
+        return Err(invalid(reason));
+    }
+
+    let closed = legacy.deadline < now;
+    let poll = Poll {
+        // Assigned when written; legacy ids overlap with existing ones.
+        id: 0,
+        title: legacy.title,
+        description: String::new(),
+        options: legacy.options,
+        creator,
+        created_at: legacy.created_at,
+        start_time: None,
+        deadline: Some(legacy.deadline),
+        status: if closed { PollStatus::Closed } else { PollStatus::Open },
+        closed_at: closed.then_some(legacy.deadline),
+        rewards: legacy.rewards,
+        voting_mode: VotingMode::SingleChoice,
+        seats: 1,
+        free_text: None,
+        questions: Vec::new(),
+        // The legacy canister rejected a second vote.
+        vote_change: VoteChangePolicy::Immutable,
+        reveal_window: None,
+        ballot_visibility: BallotVisibility::Public,
+        results_visibility: ResultsVisibility::Always,
+        private: false,
+        eligibility: None,
+        token_gate: None,
+    };
+
+    Ok(ConvertedPoll { legacy_id: legacy.id, poll, ballots })
+}
+
+fn write(converted: ConvertedPoll) -> u64 {
+    let ConvertedPoll { legacy_id, mut poll, ballots } = converted;
+    let id = next_poll_id();
+    poll.id = id;
+
+    let mut tally = Tally {
+        counts: vec![0; poll.options.len()],
+        unique_voters: ballots.len() as u64,
+        finalized_at: poll.closed_at,
+    };
+
+    VOTES.with(|votes| {
+        let mut votes = votes.borrow_mut();
+        VOTER_POLLS.with(|index| {
+            let mut index = index.borrow_mut();
+            for &(voter, option_index) in &ballots {
+                // The legacy canister did not record when a vote was cast.
+                let vote = Vote {
+                    poll_id: id,
+                    voter,
+                    choices: vec![option_index],
+                    votes: Vec::new(),
+                    value: None,
+                    voted_at: poll.created_at,
+                    revision: 0,
+                    weight: 1,
+                };
+                votes.insert((id, PrincipalKey(voter)), Envelope::new(&vote));
+                index.insert((PrincipalKey(voter), id), ());
+                tally.counts[option_index as usize] += 1;
+            }
+        });
+    });
+
+    TALLIES.with(|tallies| tallies.borrow_mut().insert(id, tally));
+    LEGACY_POLL_IDS.with(|ids| ids.borrow_mut().insert(legacy_id, id));
+    insert_poll(poll, ballots.len() as u64);
+
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::after_upgrade;
+
+    fn principal(byte: u8) -> Principal {
+        Principal::from_slice(&[byte])
+    }
+
+    fn legacy_poll(id: u64, deadline: u64, ballots: &[(u8, usize)]) -> LegacyPoll {
+        let mut votes = vec![0; 3];
+        for &(_, option) in ballots {
+            votes[option] += 1;
+        }
+
+        LegacyPoll {
+            id,
+            creator: principal(100).to_text(),
+            title: format!("Legacy poll {}", id),
+            options: vec!["A".to_string(), "B".to_string(), "C".to_string()],
+            rewards: Some(RewardInfo {
+                token_type: "PPS".to_string(),
+                reward_amount: 10,
+                reward_count: 5,
+            }),
+            deadline,
+            votes,
+            voters: ballots.iter().map(|&(voter, option)| (principal(voter).to_text(), option)).collect(),
+            created_at: 10,
+        }
+    }
+
+    #[test]
+    fn imported_polls_survive_upgrade() {
+        let now = 1_000;
+        let polls = vec![
+            legacy_poll(1, 5_000, &[(1, 0), (2, 0), (3, 2)]),
+            legacy_poll(2, 500, &[(1, 1)]),
+        ];
+        let imported = import_polls(polls.clone(), now).unwrap();
+        assert_eq!(imported.len(), 2);
+
+        after_upgrade(move || {
+            let open = find_poll(imported[0].poll_id).unwrap();
+            assert_eq!(open.title, "Legacy poll 1");
+            assert_eq!(open.creator, principal(100));
+            assert_eq!(open.status, PollStatus::Open);
+            assert_eq!(open.rewards.as_ref().map(|r| r.reward_count), Some(5));
+
+            let closed = find_poll(imported[1].poll_id).unwrap();
+            assert_eq!(closed.status, PollStatus::Closed);
+            assert_eq!(closed.closed_at, Some(500));
+
+            let vote = load_vote(open.id, principal(3)).unwrap().unwrap();
+            assert_eq!(vote.choices, vec![2]);
+            let vote_count = VOTES.with(|v| v.borrow().range(poll_votes_range(open.id, None)).count());
+            assert_eq!(vote_count, 3);
+
+            let tally = TALLIES.with(|t| t.borrow().get(&open.id)).unwrap();
+            assert_eq!(tally.counts, vec![2, 0, 1]);
+            assert_eq!(tally.unique_voters, 3);
+
+            let voted_in = VOTER_POLLS.with(|index| {
+                index.borrow()
+                    .range(principal_polls_range(principal(1), None))
+                    .count()
+            });
+            assert_eq!(voted_in, 2);
+            assert!(POLL_SUMMARIES.with(|s| s.borrow().get(&open.id)).is_some_and(|s| s.votes == 3));
+
+            // Ids keep counting from where they were, and a repeated import
+            // does not duplicate anything.
+            assert_eq!(NEXT_POLL_ID.with(|id| *id.borrow().get()), closed.id + 1);
+            assert_eq!(import_polls(polls, now).unwrap(), imported);
+            assert_eq!(POLLS.with(|p| p.borrow().len()), 2);
+        });
+    }
+
+    #[test]
+    fn malformed_batch_imports_nothing() {
+        let mut bad = legacy_poll(8, 5_000, &[(1, 0)]);
+        bad.voters.insert("not a principal".to_string(), 0);
+
+        let result = import_polls(vec![legacy_poll(7, 5_000, &[]), bad], 1_000);
+
+        assert!(matches!(result, Err(PollError::InvalidLegacyPoll { legacy_id: 8, .. })));
+        assert_eq!(POLLS.with(|p| p.borrow().len()), 0);
+    }
+
+    #[test]
+    fn duplicate_ids_in_a_batch_import_nothing() {
+        let polls = vec![legacy_poll(7, 5_000, &[]), legacy_poll(8, 5_000, &[]), legacy_poll(7, 6_000, &[])];
+
+        let result = import_polls(polls, 1_000);
+
+        assert!(matches!(result, Err(PollError::InvalidLegacyPoll { legacy_id: 7, .. })));
+        assert_eq!(POLLS.with(|p| p.borrow().len()), 0);
+        assert!(LEGACY_POLL_IDS.with(|ids| ids.borrow().is_empty()));
+    }
+
+    #[test]
+    fn counts_must_match_the_voters() {
+        let mut miscounted = legacy_poll(9, 5_000, &[(1, 0), (2, 1)]);
+        miscounted.votes = vec![2, 0, 0];
+        let mut short = legacy_poll(10, 5_000, &[(1, 0)]);
+        short.votes.pop();
+
+        for poll in [miscounted, short] {
+            let legacy_id = poll.id;
+            let result = import_polls(vec![legacy_poll(7, 5_000, &[(3, 2)]), poll], 1_000);
+            match result {
+                Err(PollError::InvalidLegacyPoll { legacy_id: id, .. }) => assert_eq!(id, legacy_id),
+                _ => panic!("legacy poll {} was not rejected", legacy_id),
+            }
+        }
+        assert_eq!(POLLS.with(|p| p.borrow().len()), 0);
+    }
+}
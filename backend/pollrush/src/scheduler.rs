@@ -0,0 +1,272 @@
+//! Timer-driven opening and closing of polls, the end of secret ballot reveal
+//! windows, and the batched counting and removal of large polls.
+//!
+//! Every pending transition is persisted in `SCHEDULE`, keyed by the time it is
+//! due, and a single timer is armed for the earliest entry. Timers do not
+//! survive upgrades, so `post_upgrade` calls [`arm`] to rebuild it from the
+//! persisted entries; anything that fell due meanwhile runs on the next tick.
+//!
+//! A tick only queues the due entries and re-arms; each one is then applied by
+//! a timer of its own. Timer callbacks run as separate messages, so a
+//! transition that traps fails on its own, without rolling back the tick and
+//! leaving [`ARMED`] naming a timer that already fired. Entries leave
+//! `SCHEDULE` in the same message that persists their transition: one that
+//! trapped, or whose timer an upgrade dropped, is applied again after the next
+//! upgrade.
+
+use super::*;
+use ic_cdk_timers::TimerId;
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+/// Upper bound on transitions applied per timer tick, so a burst of polls
+/// sharing a deadline cannot exhaust the instruction limit.
+const MAX_EVENTS_PER_TICK: usize = 100;
+
+thread_local! {
+    static ARMED: Cell<Option<(u64, TimerId)>> = const { Cell::new(None) };
+    /// Entries a timer has been set to apply, which later ticks skip.
+    static QUEUED: RefCell<BTreeSet<(u64, u64)>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PollEvent {
+    Open,
+    Close,
+    /// The reveal window of a closed secret ballot poll ends.
+    EndReveal,
+    /// The next page of a closed ranked poll's ballots is counted.
+    Count,
+    /// The next batch of a deleted poll's records is removed.
+    Purge,
+}
+
+impl Storable for PollEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(bincode::serialize(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        bincode::deserialize(&bytes).unwrap()
+    }
+}
+
+impl BoundedStorable for PollEvent {
+    const MAX_SIZE: u32 = 8;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Persists the start and deadline transitions of a new poll. Callers follow
+/// up with [`arm`] once their writes are done.
+pub(crate) fn schedule(poll: &Poll) {
+    SCHEDULE.with(|schedule| {
+        let mut schedule = schedule.borrow_mut();
+        if let (PollStatus::Scheduled, Some(start_time)) = (poll.status, poll.start_time) {
+            schedule.insert((start_time, poll.id), PollEvent::Open);
+        }
+        if let (PollStatus::Scheduled | PollStatus::Open, Some(deadline)) = (poll.status, poll.deadline) {
+            schedule.insert((deadline, poll.id), PollEvent::Close);
+        }
+    });
+}
+
+/// Persists the end of a closed poll's reveal window and arms the timer.
+pub(crate) fn schedule_reveal_end(poll_id: u64, at: u64) {
+    SCHEDULE.with(|schedule| schedule.borrow_mut().insert((at, poll_id), PollEvent::EndReveal));
+    arm();
+}
+
+/// Persists the counting of a closed ranked poll and arms the timer.
+pub(crate) fn schedule_count(poll_id: u64, at: u64) {
+    SCHEDULE.with(|schedule| schedule.borrow_mut().insert((at, poll_id), PollEvent::Count));
+    arm();
+}
+
+/// Persists the removal of a deleted poll's records. Callers follow up with
+/// [`arm`].
+pub(crate) fn schedule_purge(poll_id: u64, at: u64) {
+    SCHEDULE.with(|schedule| schedule.borrow_mut().insert((at, poll_id), PollEvent::Purge));
+}
+
+/// Drops the pending transitions of a deleted poll.
+pub(crate) fn unschedule(poll: &Poll) {
+    let reveal_end = poll.closed_at.zip(poll.reveal_window).map(|(at, window)| at.saturating_add(window));
+    SCHEDULE.with(|schedule| {
+        let mut schedule = schedule.borrow_mut();
+        for at in [poll.start_time, poll.deadline, poll.closed_at, reveal_end].into_iter().flatten() {
+            schedule.remove(&(at, poll.id));
+        }
+    });
+}
+
+/// Makes sure a timer is set for the earliest pending transition.
+pub(crate) fn arm() {
+    let next = QUEUED.with(|queued| {
+        let queued = queued.borrow();
+        SCHEDULE.with(|schedule| {
+            schedule.borrow().iter().map(|(key, _)| key).find(|key| !queued.contains(key))
+        })
+    });
+    let Some((next, _)) = next else {
+        return;
+    };
+
+    if let Some((armed_at, timer)) = ARMED.get() {
+        if armed_at <= next {
+            return;
+        }
+        ic_cdk_timers::clear_timer(timer);
+    }
+
+    let delay = Duration::from_nanos(next.saturating_sub(time()));
+    ARMED.set(Some((next, ic_cdk_timers::set_timer(delay, run))));
+}
+
+fn run() {
+    ARMED.set(None);
+    for ((at, poll_id), event) in take_due(time()) {
+        ic_cdk_timers::set_timer(Duration::ZERO, move || apply(poll_id, event, at));
+    }
+    arm();
+}
+
+/// Returns the earliest transitions due by `now` that are not queued yet, and
+/// queues them. They stay in `SCHEDULE` until applied.
+fn take_due(now: u64) -> Vec<((u64, u64), PollEvent)> {
+    QUEUED.with(|queued| {
+        let mut queued = queued.borrow_mut();
+        let due: Vec<_> = SCHEDULE.with(|schedule| {
+            schedule.borrow()
+                .range(..=(now, u64::MAX))
+                .filter(|(key, _)| !queued.contains(key))
+                .take(MAX_EVENTS_PER_TICK)
+                .collect()
+        });
+        queued.extend(due.iter().map(|(key, _)| *key));
+        due
+    })
+}
+
+/// Applies a queued transition and takes it off the schedule, unless it has
+/// more batches to go.
+fn apply(poll_id: u64, event: PollEvent, at: u64) {
+    let key = (at, poll_id);
+    let done = transition(poll_id, event, at);
+    QUEUED.with(|queued| queued.borrow_mut().remove(&key));
+    SCHEDULE.with(|schedule| {
+        let mut schedule = schedule.borrow_mut();
+        // Closing may have replaced the entry with the poll's next one.
+        if done && schedule.get(&key) == Some(event) {
+            schedule.remove(&key);
+        }
+    });
+    if SCHEDULE.with(|schedule| schedule.borrow().contains_key(&key)) {
+        arm();
+    }
+}
+
+/// Persists a due transition unless the poll has meanwhile been deleted or
+/// moved to another status by its creator. A poll that cannot be decoded is
+/// left alone; its status is still derived lazily from its deadline. Returns
+/// whether the entry is done with.
+fn transition(poll_id: u64, event: PollEvent, at: u64) -> bool {
+    if event == PollEvent::Purge {
+        return purge_page(poll_id, MAX_RECORDS_PER_PURGE);
+    }
+    let Ok(Some(mut poll)) = load_poll(poll_id) else {
+        return true;
+    };
+    match event {
+        PollEvent::EndReveal => {
+            finalize_tally(poll_id, at);
+            return true;
+        }
+        PollEvent::Count => return count_ranked_page(&poll, MAX_BALLOTS_PER_COUNT),
+        PollEvent::Open | PollEvent::Close | PollEvent::Purge => {}
+    }
+
+    match (event, poll.status) {
+        (PollEvent::Open, PollStatus::Scheduled) => {
+            poll.status = PollStatus::Open;
+        }
+        (PollEvent::Close, PollStatus::Open | PollStatus::Scheduled) => {
+            poll.status = PollStatus::Closed;
+            poll.closed_at = Some(at);
+        }
+        _ => return true,
+    }
+
+    let status = poll.status;
+    store_poll(&poll);
+    update_summary(poll_id, |summary| summary.status = status);
+
+    if status == PollStatus::Closed {
+        on_poll_closed(&poll);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{after_upgrade, open_poll};
+
+    #[test]
+    fn ticks_take_a_bounded_batch_of_due_transitions() {
+        SCHEDULE.with(|schedule| {
+            let mut schedule = schedule.borrow_mut();
+            for poll_id in 0..MAX_EVENTS_PER_TICK as u64 + 5 {
+                schedule.insert((10 + poll_id, poll_id), PollEvent::Close);
+            }
+            schedule.insert((1_000, 0), PollEvent::Open);
+        });
+
+        let first = take_due(500);
+        assert_eq!(first.len(), MAX_EVENTS_PER_TICK);
+        assert_eq!((first[0].0, first[MAX_EVENTS_PER_TICK - 1].0), ((10, 0), (109, 99)));
+        assert_eq!(take_due(500).len(), 5);
+        assert!(take_due(500).is_empty());
+        // Queued entries stay scheduled until they are applied.
+        assert_eq!(SCHEDULE.with(|schedule| schedule.borrow().len()), MAX_EVENTS_PER_TICK as u64 + 6);
+    }
+
+    #[test]
+    fn transitions_whose_timer_was_dropped_are_recovered() {
+        let poll = Poll { deadline: Some(20), ..open_poll(3, VoteChangePolicy::Changeable) };
+        schedule(&poll);
+        insert_poll(poll, 0);
+
+        // The tick queues the close, but an upgrade drops its timer.
+        assert_eq!(take_due(30), vec![((20, 3), PollEvent::Close)]);
+        assert!(take_due(30).is_empty());
+        after_upgrade(|| {
+            assert_eq!(take_due(30), vec![((20, 3), PollEvent::Close)]);
+            apply(3, PollEvent::Close, 20);
+            let closed = load_poll(3).unwrap().unwrap();
+            assert_eq!((closed.status, closed.closed_at), (PollStatus::Closed, Some(20)));
+            assert!(SCHEDULE.with(|schedule| schedule.borrow().is_empty()));
+            assert!(QUEUED.with(|queued| queued.borrow().is_empty()));
+        });
+    }
+
+    #[test]
+    fn transitions_apply_only_from_the_expected_status() {
+        let mut poll = open_poll(1, VoteChangePolicy::Changeable);
+        poll.status = PollStatus::Scheduled;
+        poll.start_time = Some(10);
+        poll.deadline = Some(20);
+        insert_poll(poll, 0);
+
+        apply(1, PollEvent::Close, 20);
+        apply(1, PollEvent::Open, 10);
+        let closed = load_poll(1).unwrap().unwrap();
+        assert_eq!((closed.status, closed.closed_at), (PollStatus::Closed, Some(20)));
+        let tally = TALLIES.with(|tallies| tallies.borrow().get(&1)).unwrap_or_default();
+        assert_eq!(tally.finalized_at, Some(20));
+
+        // Events for deleted polls are dropped.
+        apply(2, PollEvent::Close, 20);
+        assert!(load_poll(2).unwrap().is_none());
+    }
+}
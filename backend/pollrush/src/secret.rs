@@ -0,0 +1,308 @@
+//! Secret ballots by commit–reveal.
+//!
+//! While the poll is open voters only submit `SHA-256(option ‖ salt)`, with
+//! the option as a big-endian `u32`. Once it closes they have
+//! `Poll::reveal_window` nanoseconds to reveal the option and salt; a reveal
+//! that matches its commitment is recorded as an ordinary ballot. Commitments
+//! never revealed count as abstentions, and results stay hidden until the
+//! window ends.
+
+use super::*;
+use sha2::{Digest, Sha256};
+
+const HASH_BYTES: usize = 32;
+
+/// Shortest accepted salt, so that a commitment cannot be opened by trying
+/// every option.
+const MIN_SALT_BYTES: usize = 16;
+const MAX_SALT_BYTES: usize = 64;
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub(crate) struct Commitment {
+    hash: Vec<u8>,
+    committed_at: u64,
+    revealed: bool,
+    /// Times the commitment has been replaced since it was first made.
+    revision: u32,
+}
+
+impl Versioned for Commitment {
+    const VERSION: u8 = 2;
+    const MAX_SIZE: u32 = 128;
+
+    fn decode_version(version: u8, payload: &[u8]) -> Result<Self, PollError> {
+        match version {
+            1 => decode_payload::<CommitmentV1>(version, payload).map(Commitment::from),
+            Self::VERSION => decode_payload(version, payload),
+            _ => Err(unknown_version(version)),
+        }
+    }
+}
+
+/// Version 1 layout of `Commitment`, written before commitments were revised.
+#[derive(Deserialize)]
+struct CommitmentV1 {
+    hash: Vec<u8>,
+    committed_at: u64,
+    revealed: bool,
+}
+
+impl From<CommitmentV1> for Commitment {
+    fn from(commitment: CommitmentV1) -> Self {
+        Commitment {
+            hash: commitment.hash,
+            committed_at: commitment.committed_at,
+            revealed: commitment.revealed,
+            revision: 0,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct CommitArgs {
+    poll_id: u64,
+    hash: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct RevealArgs {
+    poll_id: u64,
+    option: u32,
+    salt: Vec<u8>,
+}
+
+fn commitment_hash(option: u32, salt: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(option.to_be_bytes());
+    hasher.update(salt);
+    hasher.finalize().to_vec()
+}
+
+/// Checks the settings of a new commit–reveal poll.
+pub(crate) fn validate(args: &CreatePollArgs) -> Result<(), PollError> {
+    let reason = if args.reveal_window == Some(0) {
+        "reveal_window must be positive"
+    } else if args.deadline.is_none() {
+        "secret ballots need a deadline"
+    } else if args.voting_mode.is_some_and(|mode| mode != VotingMode::SingleChoice) {
+        "secret ballots are single choice"
+    } else if args.questions.is_some() || args.free_text.is_some() {
+        "secret ballots take neither survey questions nor free-text answers"
+    } else if args.vote_change == Some(VoteChangePolicy::Revocable) {
+        "secret ballots cannot be revoked"
+    } else {
+        return Ok(());
+    };
+    Err(PollError::InvalidVotingMode { reason: reason.to_string() })
+}
+
+/// When the reveal window of a closed poll ends.
+pub(crate) fn reveal_ends_at(poll: &Poll, now: u64) -> Option<u64> {
+    let window = poll.reveal_window?;
+    match poll.status_at(now) {
+        PollStatus::Closed | PollStatus::Archived => {
+            poll.closed_at_or_deadline(now).map(|closed_at| closed_at.saturating_add(window))
+        }
+        _ => None,
+    }
+}
+
+/// Fails unless the reveal window of a secret poll has ended.
+pub(crate) fn ensure_revealed(poll: &Poll, now: u64) -> Result<(), PollError> {
+    if poll.reveal_window.is_none() {
+        return Ok(());
+    }
+    match reveal_ends_at(poll, now) {
+        Some(ends_at) if now > ends_at => Ok(()),
+        _ => Err(PollError::ResultsNotFinal),
+    }
+}
+
+fn not_secret() -> PollError {
+    PollError::InvalidVotingMode { reason: "the poll does not use secret ballots".to_string() }
+}
+
+fn load_commitment(poll_id: u64, voter: Principal) -> Result<Option<Commitment>, PollError> {
+    COMMITMENTS.with(|commitments| commitments.borrow().get(&(poll_id, PrincipalKey(voter))))
+        .map(|commitment| commitment.decode())
+        .transpose()
+}
+
+fn store_commitment(poll_id: u64, voter: Principal, commitment: &Commitment) {
+    COMMITMENTS.with(|commitments| {
+        commitments.borrow_mut().insert((poll_id, PrincipalKey(voter)), Envelope::new(commitment));
+    });
+}
+
+/// Records the caller's commitment, replacing an earlier one if the poll's
+/// policy allows changes.
+pub(crate) fn commit(args: CommitArgs, caller: Principal, now: u64) -> Result<(), PollError> {
+    let poll = find_poll(args.poll_id)?;
+    if !poll.is_visible_to(caller) {
+        return Err(PollError::PollNotFound);
+    }
+    ensure_open(&poll, now)?;
+    if poll.reveal_window.is_none() {
+        return Err(not_secret());
+    }
+    if args.hash.len() != HASH_BYTES {
+        return Err(PollError::InvalidBallot {
+            reason: "commitments are 32-byte SHA-256 hashes".to_string(),
+        });
+    }
+    let previous = load_commitment(poll.id, caller)?;
+    if previous.is_some() && !poll.vote_change.allows_change() {
+        return Err(PollError::AlreadyVoted);
+    }
+
+    store_commitment(poll.id, caller, &Commitment {
+        hash: args.hash,
+        committed_at: now,
+        revealed: false,
+        revision: previous.map_or(0, |previous| previous.revision + 1),
+    });
+    VOTER_POLLS.with(|index| {
+        index.borrow_mut().insert((PrincipalKey(caller), poll.id), ());
+    });
+    Ok(())
+}
+
+/// Opens the caller's commitment and records the ballot it hides.
+pub(crate) fn reveal(args: RevealArgs, caller: Principal, now: u64) -> Result<(), PollError> {
+    let poll = find_poll(args.poll_id)?;
+    if !poll.is_visible_to(caller) {
+        return Err(PollError::PollNotFound);
+    }
+    if poll.reveal_window.is_none() {
+        return Err(not_secret());
+    }
+    match reveal_ends_at(&poll, now) {
+        None => return Err(PollError::PollNotOpen { status: poll.status_at(now) }),
+        Some(ended_at) if now > ended_at => return Err(PollError::RevealWindowClosed { ended_at }),
+        Some(_) => {}
+    }
+
+    let mut commitment = load_commitment(poll.id, caller)?.ok_or(PollError::NotVoted)?;
+    if commitment.revealed {
+        return Err(PollError::AlreadyVoted);
+    }
+    if args.salt.len() < MIN_SALT_BYTES || args.salt.len() > MAX_SALT_BYTES {
+        return Err(PollError::InvalidBallot {
+            reason: format!("salts are {} to {} bytes", MIN_SALT_BYTES, MAX_SALT_BYTES),
+        });
+    }
+    if commitment_hash(args.option, &args.salt) != commitment.hash {
+        return Err(PollError::RevealMismatch);
+    }
+
+    let vote = Vote {
+        poll_id: poll.id,
+        voter: caller,
+        choices: vec![args.option],
+        votes: Vec::new(),
+        value: None,
+        voted_at: commitment.committed_at,
+        revision: commitment.revision,
+        weight: 1,
+    };
+    poll.voting_mode.validate_ballot(poll.options.len(), &vote)?;
+
+    commitment.revealed = true;
+    store_commitment(poll.id, caller, &commitment);
+    store_vote(&vote);
+
+    let voters = TALLIES.with(|tallies| {
+        let mut tallies = tallies.borrow_mut();
+        let mut tally = tallies.get(&poll.id).unwrap_or_default();
+        tally.record(poll.options.len(), None, &poll.voting_mode.counted(&vote));
+        let voters = tally.unique_voters;
+        tallies.insert(poll.id, tally);
+        voters
+    });
+    update_summary(poll.id, |summary| summary.votes = voters);
+
+    Ok(())
+}
+
+/// Commitments of a poll that were never revealed, which are counted as
+/// abstentions once the reveal window ends.
+pub(crate) fn abstentions(poll_id: u64) -> u64 {
+    COMMITMENTS.with(|commitments| {
+        commitments.borrow()
+            .range(poll_votes_range(poll_id, None))
+            .filter(|(_, commitment)| commitment.decode().is_ok_and(|commitment| !commitment.revealed))
+            .count() as u64
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_matching_reveals_are_counted() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let carol = Principal::from_slice(&[3]);
+        let mut poll = crate::tests::open_poll(30, VoteChangePolicy::Immutable);
+        poll.deadline = Some(100);
+        poll.reveal_window = Some(50);
+        insert_poll(poll, 0);
+
+        let salt = vec![7; MIN_SALT_BYTES];
+        let commit_to = |voter, option| {
+            commit(CommitArgs { poll_id: 30, hash: commitment_hash(option, &salt) }, voter, 10)
+        };
+        commit_to(alice, 2).unwrap();
+        commit_to(bob, 0).unwrap();
+        commit_to(carol, 1).unwrap();
+        assert_eq!(commit_to(alice, 1), Err(PollError::AlreadyVoted));
+
+        let reveal_as = |voter, option, now| {
+            reveal(RevealArgs { poll_id: 30, option, salt: salt.clone() }, voter, now)
+        };
+        assert!(matches!(reveal_as(alice, 2, 50), Err(PollError::PollNotOpen { .. })));
+        reveal_as(alice, 2, 120).unwrap();
+        assert_eq!(reveal_as(alice, 2, 121), Err(PollError::AlreadyVoted));
+        assert_eq!(reveal_as(bob, 1, 121), Err(PollError::RevealMismatch));
+        assert_eq!(reveal_as(bob, 0, 151), Err(PollError::RevealWindowClosed { ended_at: 150 }));
+
+        let tally = TALLIES.with(|tallies| tallies.borrow().get(&30)).unwrap_or_default();
+        assert_eq!((tally.counts, tally.unique_voters), (vec![0, 0, 1], 1));
+        assert_eq!(abstentions(30), 2);
+
+        let poll = find_poll(30).unwrap();
+        assert_eq!(ensure_revealed(&poll, 150), Err(PollError::ResultsNotFinal));
+        assert_eq!(ensure_revealed(&poll, 151), Ok(()));
+
+        // Revealed ballots stay out of the listings until the window ends, even
+        // for the poll's managers.
+        assert!(matches!(votes_page(30, None, 10, carol, 150), Err(PollError::ResultsNotFinal)));
+        let creator = poll.creator;
+        assert!(matches!(answers_page(30, None, 10, creator, 150), Err(PollError::ResultsNotFinal)));
+        assert_eq!(votes_page(30, None, 10, carol, 151).unwrap().votes.len(), 1);
+        assert!(answers_page(30, None, 10, creator, 151).is_ok());
+    }
+
+    #[test]
+    fn commitments_follow_the_change_policy() {
+        let alice = Principal::from_slice(&[1]);
+        let mut poll = crate::tests::open_poll(31, VoteChangePolicy::Changeable);
+        poll.deadline = Some(100);
+        poll.reveal_window = Some(50);
+        insert_poll(poll, 0);
+
+        let salt = vec![7; MIN_SALT_BYTES];
+        let commit_to = |option| {
+            commit(CommitArgs { poll_id: 31, hash: commitment_hash(option, &salt) }, alice, 10)
+        };
+        commit_to(0).unwrap();
+        commit_to(1).unwrap();
+        assert_eq!(load_commitment(31, alice).unwrap().map(|commitment| commitment.revision), Some(1));
+        // Secret polls are never revocable, so commitments cannot be withdrawn.
+        assert_eq!(withdraw_vote(31, alice, 20), Err(PollError::VoteChangeNotAllowed));
+
+        reveal(RevealArgs { poll_id: 31, option: 1, salt }, alice, 120).unwrap();
+        assert_eq!(load_vote(31, alice).unwrap().map(|vote| vote.revision), Some(1));
+    }
+}
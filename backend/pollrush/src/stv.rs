@@ -0,0 +1,234 @@
+//! Single transferable vote counting for polls that elect several seats.
+//!
+//! Uses the Droop quota and fractional (Gregory) surplus transfers: when an
+//! option is elected with more than the quota, every ballot counting for it
+//! passes on the same fraction of its value to its next preference. Values
+//! are fixed point in `VOTE_UNIT`ths of a ballot and rounded down.
+
+use super::*;
+
+pub(crate) const VOTE_UNIT: u64 = 1_000_000;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SurplusTransfer {
+    option: u32,
+    surplus: u64,
+    /// Value each ballot for `option` keeps when it moves on, in vote units.
+    transfer_value: u64,
+}
+
+/// One round of the count. Exactly one of `elected` and `eliminated` is set.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct StvRound {
+    /// Value counting for each continuing option at the start of the round.
+    tallies: Vec<u64>,
+    /// Value of ballots with no continuing preference.
+    exhausted: u64,
+    elected: Vec<u32>,
+    eliminated: Option<u32>,
+    transfers: Vec<SurplusTransfer>,
+}
+
+/// The full count of a closed STV poll, computed once when it closes.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub(crate) struct ElectionReport {
+    poll_id: u64,
+    seats: u32,
+    ballots: u64,
+    /// All values in the report are in units of `1 / vote_unit` ballots.
+    vote_unit: u64,
+    quota: u64,
+    rounds: Vec<StvRound>,
+    /// Elected options in the order they were elected.
+    pub(crate) elected: Vec<u32>,
+}
+
+impl Versioned for ElectionReport {
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 128 * 1024; // 128KB, a full log for the largest ranked poll
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Standing {
+    Hopeful,
+    Elected,
+    Excluded,
+}
+
+/// Counts `ballots`, each distinct ranking paired with the number of voters
+/// who cast it. Identical ballots always transfer together, so counting them
+/// as one group gives the same result as counting them one by one.
+pub(crate) fn count(poll_id: u64, seats: u32, options: usize, ballots: &[(Vec<u32>, u64)]) -> ElectionReport {
+    let total: u64 = ballots.iter().map(|(_, voters)| voters).sum();
+    let quota = (total / (seats as u64 + 1) + 1) * VOTE_UNIT;
+    let mut weights = vec![VOTE_UNIT; ballots.len()];
+    let mut standing = vec![Standing::Hopeful; options];
+    let mut elected = Vec::new();
+    let mut rounds: Vec<StvRound> = Vec::new();
+
+    // Without ballots every option would be eliminated but the last few,
+    // which then take the seats unopposed.
+    while total > 0 && elected.len() < seats as usize {
+        let mut tallies = vec![0u64; options];
+        let mut exhausted = 0;
+        let piles: Vec<Option<u32>> = ballots.iter()
+            .zip(&weights)
+            .map(|((ballot, voters), &weight)| {
+                let top = ballot.iter()
+                    .copied()
+                    .find(|&choice| standing[choice as usize] == Standing::Hopeful);
+                match top {
+                    Some(choice) => tallies[choice as usize] += weight * voters,
+                    None => exhausted += weight * voters,
+                }
+                top
+            })
+            .collect();
+
+        let mut hopeful: Vec<u32> = (0..options as u32)
+            .filter(|&option| standing[option as usize] == Standing::Hopeful)
+            .collect();
+        // Stable sort: equal tallies keep option order.
+        hopeful.sort_by_key(|&option| std::cmp::Reverse(tallies[option as usize]));
+
+        let mut round = StvRound {
+            tallies: tallies.clone(),
+            exhausted,
+            elected: Vec::new(),
+            eliminated: None,
+            transfers: Vec::new(),
+        };
+        let open_seats = seats as usize - elected.len();
+
+        if hopeful.len() <= open_seats {
+            // Too few options remain to contest the open seats.
+            round.elected = hopeful;
+        } else {
+            round.elected = hopeful.iter()
+                .copied()
+                .filter(|&option| tallies[option as usize] >= quota)
+                .take(open_seats)
+                .collect();
+
+            for &option in &round.elected {
+                let total = tallies[option as usize];
+                let surplus = total - quota;
+                for (weight, pile) in weights.iter_mut().zip(&piles) {
+                    if *pile == Some(option) {
+                        *weight = scale(*weight, surplus, total);
+                    }
+                }
+                round.transfers.push(SurplusTransfer {
+                    option,
+                    surplus,
+                    transfer_value: scale(VOTE_UNIT, surplus, total),
+                });
+            }
+
+            if round.elected.is_empty() {
+                let loser = lowest(&hopeful, &tallies, &rounds);
+                standing[loser as usize] = Standing::Excluded;
+                round.eliminated = Some(loser);
+            }
+        }
+
+        for &option in &round.elected {
+            standing[option as usize] = Standing::Elected;
+        }
+        elected.extend_from_slice(&round.elected);
+        rounds.push(round);
+    }
+
+    ElectionReport {
+        poll_id,
+        seats,
+        ballots: total,
+        vote_unit: VOTE_UNIT,
+        quota,
+        rounds,
+        elected,
+    }
+}
+
+fn scale(value: u64, numerator: u64, denominator: u64) -> u64 {
+    (value as u128 * numerator as u128 / denominator as u128) as u64
+}
+
+/// The hopeful option to eliminate: the fewest votes this round, ties broken
+/// by the fewest votes in the latest earlier round where the tied options
+/// differ, then by eliminating the option listed last.
+fn lowest(hopeful: &[u32], tallies: &[u64], rounds: &[StvRound]) -> u32 {
+    let fewest = hopeful.iter().map(|&option| tallies[option as usize]).min().unwrap_or(0);
+    let mut tied: Vec<u32> = hopeful.iter()
+        .copied()
+        .filter(|&option| tallies[option as usize] == fewest)
+        .collect();
+    tied.sort_unstable();
+
+    for round in rounds.iter().rev() {
+        if tied.len() == 1 {
+            break;
+        }
+        let fewest = tied.iter().map(|&option| round.tallies[option as usize]).min().unwrap_or(0);
+        tied.retain(|&option| round.tallies[option as usize] == fewest);
+    }
+    tied[tied.len() - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn surplus_transfers_and_eliminations_fill_every_seat() {
+        let ballots = [(vec![0, 1], 6), (vec![2], 1), (vec![3, 2], 3)];
+
+        let report = count(7, 2, 4, &ballots);
+
+        // Droop quota for 10 ballots and 2 seats.
+        assert_eq!(report.quota, 4 * VOTE_UNIT);
+        assert_eq!(report.elected, vec![0, 3]);
+        assert_eq!(report.rounds.len(), 4);
+
+        let first = &report.rounds[0];
+        assert_eq!(first.elected, vec![0]);
+        assert_eq!(first.transfers, vec![SurplusTransfer {
+            option: 0,
+            surplus: 2 * VOTE_UNIT,
+            transfer_value: VOTE_UNIT / 3,
+        }]);
+
+        // The six ballots for 0 now carry a third of a vote each to 1.
+        assert_eq!(report.rounds[1].tallies[1], 6 * (VOTE_UNIT / 3));
+        assert_eq!(report.rounds[1].eliminated, Some(2));
+        assert_eq!(report.rounds[2].exhausted, VOTE_UNIT);
+        assert_eq!(report.rounds[2].eliminated, Some(1));
+        assert_eq!(report.rounds[3].elected, vec![3]);
+    }
+
+    #[test]
+    fn nobody_is_elected_without_ballots() {
+        for ballots in [Vec::new(), vec![(vec![0, 1], 0)]] {
+            let report = count(7, 2, 4, &ballots);
+            assert!(report.elected.is_empty());
+            assert!(report.rounds.is_empty());
+            assert_eq!(report.ballots, 0);
+        }
+    }
+
+    #[test]
+    fn ties_are_broken_by_earlier_rounds_then_option_order() {
+        let tallies = vec![5, 2, 2, 2];
+        let rounds = vec![StvRound {
+            tallies: vec![5, 2, 1, 2],
+            exhausted: 0,
+            elected: Vec::new(),
+            eliminated: None,
+            transfers: Vec::new(),
+        }];
+
+        assert_eq!(lowest(&[0, 1, 2, 3], &tallies, &rounds), 2);
+        assert_eq!(lowest(&[0, 1, 3], &tallies, &rounds), 3);
+        assert_eq!(lowest(&[0, 1, 3], &tallies, &[]), 3);
+    }
+}
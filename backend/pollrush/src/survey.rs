@@ -0,0 +1,481 @@
+//! Surveys: polls that ask an ordered list of questions, answered all at once
+//! by `submit_survey`.
+//!
+//! Each question keeps its own tally in `SURVEY_TALLIES`, while the poll's
+//! own tally counts respondents.
+
+use super::*;
+
+/// Upper bound on the questions of a survey, which keeps a full response
+/// inside `SurveyResponse::MAX_SIZE`.
+pub(crate) const MAX_QUESTIONS: usize = 50;
+
+/// Shows a question only when the answer to an earlier `question` selected
+/// one of `choices`.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Condition {
+    question: u32,
+    choices: Vec<u32>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub(crate) struct Question {
+    prompt: String,
+    options: Vec<String>,
+    /// Single choice, multiple choice or scale.
+    voting_mode: VotingMode,
+    /// Must be answered whenever it is shown.
+    required: bool,
+    show_if: Option<Condition>,
+}
+
+impl Question {
+    /// Checks the question, given the questions before it.
+    pub(crate) fn validate(&self, config: &PollConfig, earlier: &[Question]) -> Result<(), PollError> {
+        if self.prompt.trim().is_empty() {
+            return Err(PollError::EmptyTitle);
+        }
+        if self.prompt.chars().count() > config.max_title_len as usize {
+            return Err(PollError::TitleTooLong { max: config.max_title_len });
+        }
+        if matches!(self.voting_mode, VotingMode::Ranked { .. } | VotingMode::Quadratic { .. }) {
+            return Err(PollError::InvalidVotingMode {
+                reason: "survey questions take single choice, multiple choice or scale answers".to_string(),
+            });
+        }
+        config.validate_options(&self.options, self.voting_mode, 1)?;
+
+        let Some(condition) = &self.show_if else {
+            return Ok(());
+        };
+        let reason = match earlier.get(condition.question as usize) {
+            None => "conditions must refer to an earlier question",
+            Some(question) if !question.voting_mode.takes_options() => {
+                "conditions must refer to a choice question"
+            }
+            Some(question) if condition.choices.is_empty()
+                || condition.choices.len() > question.options.len()
+                || condition.choices.iter().any(|&choice| choice as usize >= question.options.len()) => {
+                "conditions must list options of their question"
+            }
+            Some(_) => return Ok(()),
+        };
+        Err(PollError::InvalidCondition { reason: reason.to_string() })
+    }
+}
+
+/// The answer to one question of a survey, as submitted and stored.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub(crate) struct SurveyAnswer {
+    question: u32,
+    choices: Vec<u32>,
+    /// The answer to a scale question, which takes no choices.
+    value: Option<i64>,
+}
+
+impl SurveyAnswer {
+    /// The answer as a ballot for its question alone.
+    fn ballot(&self, poll_id: u64, voter: Principal, voted_at: u64) -> Vote {
+        Vote {
+            poll_id,
+            voter,
+            choices: self.choices.clone(),
+            votes: Vec::new(),
+            value: self.value,
+            voted_at,
+            revision: 0,
+            weight: 1,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub(crate) struct SurveyResponse {
+    voter: Principal,
+    /// Answers to the questions shown, by ascending question index.
+    answers: Vec<SurveyAnswer>,
+    submitted_at: u64,
+    /// Times the response has been replaced since it was first submitted.
+    revision: u32,
+}
+
+impl Versioned for SurveyResponse {
+    const VERSION: u8 = 2;
+    const MAX_SIZE: u32 = 32 * 1024; // 32KB, every choice of every question
+
+    fn decode_version(version: u8, payload: &[u8]) -> Result<Self, PollError> {
+        match version {
+            1 => decode_payload::<SurveyResponseV1>(version, payload).map(SurveyResponse::from),
+            Self::VERSION => decode_payload(version, payload),
+            _ => Err(unknown_version(version)),
+        }
+    }
+}
+
+/// Version 1 layout of `SurveyResponse`, written before responses were revised.
+#[derive(Deserialize)]
+struct SurveyResponseV1 {
+    voter: Principal,
+    answers: Vec<SurveyAnswer>,
+    submitted_at: u64,
+}
+
+impl From<SurveyResponseV1> for SurveyResponse {
+    fn from(response: SurveyResponseV1) -> Self {
+        SurveyResponse {
+            voter: response.voter,
+            answers: response.answers,
+            submitted_at: response.submitted_at,
+            revision: 0,
+        }
+    }
+}
+
+impl SurveyResponse {
+    fn ballots(&self, poll_id: u64, questions: usize) -> Vec<Option<Vote>> {
+        let mut ballots: Vec<Option<Vote>> = (0..questions).map(|_| None).collect();
+        for answer in &self.answers {
+            if let Some(ballot) = ballots.get_mut(answer.question as usize) {
+                *ballot = Some(answer.ballot(poll_id, self.voter, self.submitted_at));
+            }
+        }
+        ballots
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct SubmitSurveyArgs {
+    poll_id: u64,
+    /// Answers to the questions shown, by ascending question index.
+    answers: Vec<SurveyAnswer>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct QuestionResults {
+    question: u32,
+    options: Vec<OptionResult>,
+    /// Respondents who answered the question.
+    answered: u64,
+    /// Indices of the leading options; empty for scale questions.
+    winners: Vec<u32>,
+    scale: Option<ScaleStats>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub(crate) struct SurveyResults {
+    poll_id: u64,
+    respondents: u64,
+    questions: Vec<QuestionResults>,
+    finalized_at: Option<u64>,
+}
+
+fn invalid_answer(index: u32, error: PollError) -> PollError {
+    PollError::InvalidQuestion { index, error: Box::new(error) }
+}
+
+/// Checks `response` against a survey's questions and their conditions,
+/// returning one ballot per question, `None` where it was not answered.
+fn validate_response(
+    poll_id: u64,
+    questions: &[Question],
+    response: &SurveyResponse,
+) -> Result<Vec<Option<Vote>>, PollError> {
+    for (position, answer) in response.answers.iter().enumerate() {
+        let index = answer.question;
+        let Some(question) = questions.get(index as usize) else {
+            return Err(invalid_answer(index, PollError::InvalidBallot {
+                reason: "the survey has no such question".to_string(),
+            }));
+        };
+        if position > 0 && response.answers[position - 1].question >= index {
+            return Err(invalid_answer(index, PollError::InvalidBallot {
+                reason: "answers must follow question order, one per question".to_string(),
+            }));
+        }
+        let ballot = answer.ballot(poll_id, response.voter, response.submitted_at);
+        question.voting_mode
+            .validate_ballot(question.options.len(), &ballot)
+            .map_err(|error| invalid_answer(index, error))?;
+    }
+
+    let ballots = response.ballots(poll_id, questions.len());
+    for (index, question) in questions.iter().enumerate() {
+        let shown = question.show_if.as_ref().is_none_or(|condition| {
+            ballots[condition.question as usize]
+                .as_ref()
+                .is_some_and(|ballot| ballot.choices.iter().any(|choice| condition.choices.contains(choice)))
+        });
+        match (shown, &ballots[index]) {
+            (false, Some(_)) => {
+                return Err(invalid_answer(index as u32, PollError::InvalidBallot {
+                    reason: "the question is not shown for these answers".to_string(),
+                }));
+            }
+            (true, None) if question.required => {
+                return Err(PollError::MissingAnswer { question: index as u32 });
+            }
+            _ => {}
+        }
+    }
+    Ok(ballots)
+}
+
+/// Records the caller's answers to every question of a survey, replacing any
+/// earlier response if the poll's policy allows changes. Nothing is written
+/// unless the whole response is valid.
+pub(crate) fn submit(args: SubmitSurveyArgs, caller: Principal, now: u64) -> Result<(), PollError> {
+    let poll = find_poll(args.poll_id)?;
+    if !poll.is_visible_to(caller) {
+        return Err(PollError::PollNotFound);
+    }
+    ensure_open(&poll, now)?;
+    if poll.questions.is_empty() {
+        return Err(PollError::InvalidVotingMode { reason: "the poll is not a survey".to_string() });
+    }
+
+    let previous = load_response(poll.id, caller)?;
+    if previous.is_some() && !poll.vote_change.allows_change() {
+        return Err(PollError::VoteChangeNotAllowed);
+    }
+    let response = SurveyResponse {
+        voter: caller,
+        answers: args.answers,
+        submitted_at: now,
+        revision: previous.as_ref().map_or(0, |previous| previous.revision + 1),
+    };
+    let ballots = validate_response(poll.id, &poll.questions, &response)?;
+    let previous = previous.map(|previous| previous.ballots(poll.id, poll.questions.len()));
+
+    SURVEY_RESPONSES.with(|responses| {
+        responses.borrow_mut().insert((poll.id, PrincipalKey(caller)), Envelope::new(&response));
+    });
+    VOTER_POLLS.with(|index| {
+        index.borrow_mut().insert((PrincipalKey(caller), poll.id), ());
+    });
+
+    record_answers(&poll, previous.as_deref(), &ballots);
+
+    let respondents = TALLIES.with(|tallies| {
+        let mut tallies = tallies.borrow_mut();
+        let mut tally = tallies.get(&poll.id).unwrap_or_default();
+        tally.record(0, previous.as_ref().map(|_| &[][..]), &[]);
+        let respondents = tally.unique_voters;
+        tallies.insert(poll.id, tally);
+        respondents
+    });
+    update_summary(poll.id, |summary| summary.votes = respondents);
+
+    Ok(())
+}
+
+/// Removes the caller's response to a survey whose policy lets votes be
+/// revoked, along with its answers from every question tally.
+pub(crate) fn withdraw(poll: &Poll, caller: Principal) -> Result<(), PollError> {
+    let previous = load_response(poll.id, caller)?.ok_or(PollError::NotVoted)?;
+    let previous = previous.ballots(poll.id, poll.questions.len());
+
+    SURVEY_RESPONSES.with(|responses| responses.borrow_mut().remove(&(poll.id, PrincipalKey(caller))));
+    VOTER_POLLS.with(|index| index.borrow_mut().remove(&(PrincipalKey(caller), poll.id)));
+
+    let unanswered: Vec<Option<Vote>> = (0..poll.questions.len()).map(|_| None).collect();
+    record_answers(poll, Some(&previous), &unanswered);
+
+    let respondents = TALLIES.with(|tallies| {
+        let mut tallies = tallies.borrow_mut();
+        let mut tally = tallies.get(&poll.id).unwrap_or_default();
+        tally.withdraw(&[]);
+        let respondents = tally.unique_voters;
+        tallies.insert(poll.id, tally);
+        respondents
+    });
+    update_summary(poll.id, |summary| summary.votes = respondents);
+
+    Ok(())
+}
+
+fn load_response(poll_id: u64, voter: Principal) -> Result<Option<SurveyResponse>, PollError> {
+    SURVEY_RESPONSES.with(|responses| responses.borrow().get(&(poll_id, PrincipalKey(voter))))
+        .map(|response| response.decode())
+        .transpose()
+}
+
+/// Replaces a respondent's `previous` ballots, one per question, with `ballots`
+/// in the question tallies.
+fn record_answers(poll: &Poll, previous: Option<&[Option<Vote>]>, ballots: &[Option<Vote>]) {
+    SURVEY_TALLIES.with(|tallies| {
+        let mut tallies = tallies.borrow_mut();
+        for (index, question) in poll.questions.iter().enumerate() {
+            let mode = question.voting_mode;
+            let before = previous
+                .and_then(|previous| previous[index].as_ref())
+                .map(|ballot| mode.counted(ballot));
+            let after = ballots[index].as_ref().map(|ballot| mode.counted(ballot));
+            if before.is_none() && after.is_none() {
+                continue;
+            }
+
+            let key = (poll.id, index as u32);
+            let width = mode.tally_width(question.options.len());
+            let mut tally = tallies.get(&key).unwrap_or_default();
+            match (before, after) {
+                (before, Some(after)) => tally.record(width, before.as_deref(), &after),
+                (Some(before), None) => tally.withdraw(&before),
+                (None, None) => {}
+            }
+            tallies.insert(key, tally);
+        }
+    });
+}
+
+/// A single-choice question over `options`, shown when the one before it
+/// selected any of them.
+#[cfg(test)]
+pub(crate) fn chained_question(prompt: String, options: Vec<String>, first: bool) -> Question {
+    let show_if = (!first).then(|| Condition {
+        question: 0,
+        choices: (0..options.len() as u32).collect(),
+    });
+    Question { prompt, options, voting_mode: VotingMode::SingleChoice, required: false, show_if }
+}
+
+/// Key bounds covering every question tally of `poll_id`.
+pub(crate) fn question_tallies_range(poll_id: u64) -> (Bound<QuestionKey>, Bound<QuestionKey>) {
+    (Bound::Included((poll_id, 0)), Bound::Excluded((poll_id + 1, 0)))
+}
+
+pub(crate) fn results(poll: Poll) -> SurveyResults {
+    let tally = TALLIES.with(|tallies| tallies.borrow().get(&poll.id)).unwrap_or_default();
+
+    let questions = poll.questions
+        .into_iter()
+        .enumerate()
+        .map(|(index, question)| {
+            let key = (poll.id, index as u32);
+            let tally = SURVEY_TALLIES.with(|tallies| tallies.borrow().get(&key)).unwrap_or_default();
+            let mut counts = tally.counts;
+            counts.resize(question.voting_mode.tally_width(question.options.len()), 0);
+
+            let scale = match question.voting_mode {
+                VotingMode::Scale { min, step, .. } => Some(ScaleStats::from_counts(min, step, &counts)),
+                _ => None,
+            };
+            let winners = match scale {
+                Some(_) => Vec::new(),
+                None => voting::leaders(&counts),
+            };
+            QuestionResults {
+                question: index as u32,
+                options: option_results(question.options, &counts, None, tally.unique_voters),
+                answered: tally.unique_voters,
+                winners,
+                scale,
+            }
+        })
+        .collect();
+
+    SurveyResults {
+        poll_id: poll.id,
+        respondents: tally.unique_voters,
+        questions,
+        finalized_at: tally.finalized_at,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn question(options: &[&str], required: bool, show_if: Option<Condition>) -> Question {
+        Question {
+            prompt: "Question".to_string(),
+            options: options.iter().map(|option| option.to_string()).collect(),
+            voting_mode: VotingMode::SingleChoice,
+            required,
+            show_if,
+        }
+    }
+
+    fn answer(question: u32, choice: u32) -> SurveyAnswer {
+        SurveyAnswer { question, choices: vec![choice], value: None }
+    }
+
+    #[test]
+    fn conditional_questions_are_required_only_when_shown() {
+        let questions = vec![
+            question(&["Yes", "No"], true, None),
+            question(&["Red", "Blue"], true, Some(Condition { question: 0, choices: vec![0] })),
+            question(&["Cats", "Dogs"], false, None),
+        ];
+        let response = |answers| SurveyResponse {
+            voter: Principal::anonymous(),
+            answers,
+            submitted_at: 5,
+            revision: 0,
+        };
+
+        let ballots = validate_response(1, &questions, &response(vec![answer(0, 1), answer(2, 0)])).unwrap();
+        assert!(ballots[1].is_none());
+        assert_eq!(ballots[2].as_ref().map(|ballot| ballot.choices.clone()), Some(vec![0]));
+
+        assert!(matches!(
+            validate_response(1, &questions, &response(vec![answer(0, 0)])),
+            Err(PollError::MissingAnswer { question: 1 })
+        ));
+        assert!(matches!(
+            validate_response(1, &questions, &response(vec![answer(0, 1), answer(1, 0)])),
+            Err(PollError::InvalidQuestion { index: 1, .. })
+        ));
+        assert!(matches!(
+            validate_response(1, &questions, &response(vec![answer(0, 0), answer(1, 2)])),
+            Err(PollError::InvalidQuestion { index: 1, error })
+                if *error == PollError::InvalidOption { max: 1 }
+        ));
+        assert!(matches!(
+            validate_response(1, &questions, &response(vec![answer(2, 0), answer(0, 1)])),
+            Err(PollError::InvalidQuestion { index: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn responses_follow_the_change_policy() {
+        let alice = Principal::from_slice(&[1]);
+        let survey = |id, vote_change| {
+            let poll = Poll {
+                questions: vec![question(&["Yes", "No"], true, None)],
+                ..crate::tests::open_poll(id, vote_change)
+            };
+            insert_poll(poll, 0);
+        };
+        let submit_as = |poll_id, choice, now| {
+            submit(SubmitSurveyArgs { poll_id, answers: vec![answer(0, choice)] }, alice, now)
+        };
+        let counts = |poll_id| {
+            let tally = SURVEY_TALLIES.with(|tallies| tallies.borrow().get(&(poll_id, 0)));
+            let tally = tally.unwrap_or_default();
+            let respondents = crate::tests::tally(poll_id).1;
+            (tally.counts, respondents)
+        };
+
+        survey(40, VoteChangePolicy::Immutable);
+        submit_as(40, 0, 5).unwrap();
+        assert_eq!(submit_as(40, 1, 6), Err(PollError::VoteChangeNotAllowed));
+        assert_eq!(withdraw_vote(40, alice, 6), Err(PollError::VoteChangeNotAllowed));
+        assert_eq!(counts(40), (vec![1, 0], 1));
+
+        survey(41, VoteChangePolicy::Changeable);
+        submit_as(41, 0, 5).unwrap();
+        submit_as(41, 1, 6).unwrap();
+        assert_eq!(load_response(41, alice).unwrap().map(|response| response.revision), Some(1));
+        assert_eq!(counts(41), (vec![0, 1], 1));
+        assert_eq!(withdraw_vote(41, alice, 7), Err(PollError::VoteChangeNotAllowed));
+
+        survey(42, VoteChangePolicy::Revocable);
+        submit_as(42, 1, 5).unwrap();
+        withdraw_vote(42, alice, 6).unwrap();
+        assert!(load_response(42, alice).unwrap().is_none());
+        assert_eq!(counts(42), (vec![0, 0], 0));
+        assert_eq!(withdraw_vote(42, alice, 7), Err(PollError::NotVoted));
+        submit_as(42, 0, 8).unwrap();
+        assert_eq!(load_response(42, alice).unwrap().map(|response| response.revision), Some(0));
+    }
+}
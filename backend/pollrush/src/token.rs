@@ -0,0 +1,216 @@
+//! Calls to the `pps_token` canister and to ICRC-1 ledgers.
+
+use super::*;
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+
+/// `Lock` as returned by `pps_token`.
+#[derive(CandidType, Deserialize)]
+pub(crate) struct TokenLock {
+    pub(crate) amount: u64,
+    pub(crate) until: u64,
+}
+
+pub(crate) fn call_failed((code, message): (ic_cdk::api::call::RejectionCode, String)) -> PollError {
+    PollError::CanisterCallFailed {
+        reason: format!("{:?}: {}", code, message),
+    }
+}
+
+/// Calls `method` on `canister`. Tests are answered by [`mock_ledger`].
+async fn call<A, R>(canister: Principal, method: &str, args: A) -> Result<R, PollError>
+where
+    A: ArgumentEncoder,
+    R: for<'a> ArgumentDecoder<'a>,
+{
+    #[cfg(not(test))]
+    let reply = ic_cdk::call(canister, method, args).await;
+    #[cfg(test)]
+    let reply = mock_ledger::call(canister, method, args);
+    reply.map_err(call_failed)
+}
+
+/// The PPS `owner` has locked in `token` for `poll_id`.
+pub(crate) async fn get_lock(
+    token: Principal,
+    owner: Principal,
+    poll_id: u64,
+) -> Result<Option<TokenLock>, PollError> {
+    let (lock,): (Option<TokenLock>,) = call(token, "get_lock", (owner, poll_id)).await?;
+    Ok(lock)
+}
+
+/// Requirement that voters hold a minimum balance of a token when they vote.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TokenGate {
+    /// An ICRC-1 ledger from the config's `allowed_ledgers`; the configured PPS
+    /// token when `None`.
+    pub(crate) token: Option<Principal>,
+    pub(crate) min_balance: u64,
+}
+
+impl TokenGate {
+    /// Checks the token gate of a new poll against the config.
+    pub(crate) fn validate(&self, args: &CreatePollArgs, config: &PollConfig) -> Result<(), PollError> {
+        if self.min_balance == 0 {
+            return Err(invalid_gate("min_balance must be positive"));
+        }
+        self.check_token(config)?;
+        if args.questions.is_some() || args.reveal_window.is_some() {
+            // Balances are checked by `vote`, which surveys and secret ballots do not use.
+            return Err(invalid_gate("surveys and secret ballots cannot be token-gated"));
+        }
+        Ok(())
+    }
+
+    /// Fails unless the config still lets polls be gated on the token, so
+    /// ledgers taken off the allowlist are no longer called.
+    pub(crate) fn check_token(&self, config: &PollConfig) -> Result<(), PollError> {
+        match self.token {
+            Some(ledger) if !config.allows_ledger(ledger) => {
+                Err(invalid_gate("the ledger is not on the allowlist"))
+            }
+            None if config.pps_token.is_none() => Err(invalid_gate("no PPS token canister is configured")),
+            _ => Ok(()),
+        }
+    }
+
+    /// The balance `voter` holds of the gating token.
+    pub(crate) async fn balance(
+        &self,
+        pps_token: Option<Principal>,
+        voter: Principal,
+    ) -> Result<u64, PollError> {
+        match (self.token, pps_token) {
+            (Some(ledger), _) => icrc1_balance(ledger, voter).await,
+            (None, Some(token)) => pps_balance(token, voter).await,
+            (None, None) => Err(PollError::CanisterCallFailed {
+                reason: "no PPS token canister is configured".to_string(),
+            }),
+        }
+    }
+
+    /// Fails unless `balance`, as fetched before the vote, meets the minimum.
+    pub(crate) fn check(&self, balance: Option<u64>) -> Result<(), PollError> {
+        let available = balance.unwrap_or(0);
+        if available < self.min_balance {
+            return Err(PollError::InsufficientBalance { available });
+        }
+        Ok(())
+    }
+}
+
+fn invalid_gate(reason: &str) -> PollError {
+    PollError::InvalidConfig { reason: reason.to_string() }
+}
+
+/// ICRC-1 account.
+#[derive(CandidType, Deserialize)]
+pub(crate) struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+/// PPS balance of `owner` in `token`.
+async fn pps_balance(token: Principal, owner: Principal) -> Result<u64, PollError> {
+    let (balance,): (u64,) = call(token, "balance_of", (owner,)).await?;
+    Ok(balance)
+}
+
+/// Balance of the default account of `owner` on an ICRC-1 `ledger`, capped
+/// at `u64::MAX`.
+async fn icrc1_balance(ledger: Principal, owner: Principal) -> Result<u64, PollError> {
+    let account = Account { owner, subaccount: None };
+    let (balance,): (candid::Nat,) = call(ledger, "icrc1_balance_of", (account,)).await?;
+    Ok(u64::try_from(balance.0).unwrap_or(u64::MAX))
+}
+
+/// Stand-in for the `pps_token` canister and ICRC-1 ledgers in tests. Calls
+/// go through the Candid encoding both ways, as they would on the network.
+#[cfg(test)]
+pub(crate) mod mock_ledger {
+    use super::*;
+    use candid::Nat;
+    use ic_cdk::api::call::{CallResult, RejectionCode};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    thread_local! {
+        /// Balances by canister and owner.
+        static BALANCES: RefCell<BTreeMap<(Principal, Principal), Nat>> =
+            const { RefCell::new(BTreeMap::new()) };
+        static STOPPED: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+    }
+
+    /// Gives `owner` a balance of `amount` on the token or ledger `canister`.
+    pub(crate) fn set_balance(canister: Principal, owner: Principal, amount: impl Into<Nat>) {
+        BALANCES.with(|balances| balances.borrow_mut().insert((canister, owner), amount.into()));
+    }
+
+    /// Makes every later call to `canister` be rejected.
+    pub(crate) fn stop(canister: Principal) {
+        STOPPED.with(|stopped| stopped.borrow_mut().insert(canister));
+    }
+
+    pub(crate) fn call<A, R>(canister: Principal, method: &str, args: A) -> CallResult<R>
+    where
+        A: ArgumentEncoder,
+        R: for<'a> ArgumentDecoder<'a>,
+    {
+        if STOPPED.with(|stopped| stopped.borrow().contains(&canister)) {
+            return Err((RejectionCode::CanisterError, format!("canister {} is stopped", canister)));
+        }
+        let args = candid::encode_args(args).expect("arguments encode");
+        let decode_failed = |error: candid::Error| (RejectionCode::CanisterError, error.to_string());
+        let balance = |owner| BALANCES.with(|balances| balances.borrow().get(&(canister, owner)).cloned());
+        let reply = match method {
+            "balance_of" => {
+                let (owner,): (Principal,) = candid::decode_args(&args).map_err(decode_failed)?;
+                let balance = balance(owner).map_or(0, |balance| u64::try_from(balance.0).unwrap());
+                candid::encode_args((balance,))
+            }
+            "icrc1_balance_of" => {
+                let (account,): (Account,) = candid::decode_args(&args).map_err(decode_failed)?;
+                candid::encode_args((balance(account.owner).unwrap_or_default(),))
+            }
+            _ => return Err((RejectionCode::DestinationInvalid, format!("no method {}", method))),
+        };
+        candid::decode_args(&reply.expect("reply encodes")).map_err(decode_failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::resolve;
+
+    #[test]
+    fn gates_read_balances_from_their_token() {
+        let (pps, ledger) = (Principal::from_slice(&[50]), Principal::from_slice(&[60]));
+        let alice = Principal::from_slice(&[1]);
+        mock_ledger::set_balance(pps, alice, 12u64);
+        mock_ledger::set_balance(ledger, alice, candid::Nat::from(u64::MAX) + 1u64);
+
+        let pps_gate = TokenGate { token: None, min_balance: 10 };
+        assert_eq!(resolve(pps_gate.balance(Some(pps), alice)), Ok(12));
+        let unset = resolve(pps_gate.balance(None, alice));
+        assert!(matches!(unset, Err(PollError::CanisterCallFailed { .. })));
+
+        // ICRC-1 balances above `u64::MAX` are capped.
+        let ledger_gate = TokenGate { token: Some(ledger), min_balance: 10 };
+        assert_eq!(resolve(ledger_gate.balance(Some(pps), alice)), Ok(u64::MAX));
+        let bob = Principal::from_slice(&[2]);
+        assert_eq!(resolve(ledger_gate.balance(Some(pps), bob)), Ok(0));
+    }
+
+    #[test]
+    fn failed_ledger_calls_are_reported() {
+        let ledger = Principal::from_slice(&[60]);
+        mock_ledger::stop(ledger);
+
+        let gate = TokenGate { token: Some(ledger), min_balance: 10 };
+        let failed = resolve(gate.balance(None, Principal::from_slice(&[1])));
+        let reason = format!("CanisterError: canister {} is stopped", ledger);
+        assert_eq!(failed, Err(PollError::CanisterCallFailed { reason }));
+        let pps = resolve(TokenGate { token: None, ..gate }.balance(Some(ledger), Principal::anonymous()));
+        assert!(matches!(pps, Err(PollError::CanisterCallFailed { .. })));
+    }
+}
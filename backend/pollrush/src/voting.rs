@@ -0,0 +1,509 @@
+//! Voting modes: what a ballot may contain and how ballots are counted.
+
+use super::*;
+use stv::ElectionReport;
+
+/// Upper bound on the options a ballot may select or rank, which keeps the
+/// largest ballot well inside `Vote::MAX_SIZE` and a full runoff trace inside
+/// `RankedResults::MAX_SIZE`.
+const MAX_CHOICES: u32 = 100;
+
+/// Upper bound on the values a scale allows, which keeps its histogram inside
+/// `Tally::MAX_SIZE`.
+const MAX_SCALE_POINTS: u64 = 1_000;
+
+/// How a ranked poll picks its winners. The runoff trace and Borda scores are
+/// computed whatever the method.
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RankingMethod {
+    InstantRunoff,
+    Borda,
+    /// Fills `Poll::seats` seats; the count is served by `get_election_report`.
+    SingleTransferableVote,
+}
+
+/// Whether voters may change or withdraw their ballot while the poll is open.
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum VoteChangePolicy {
+    /// A ballot is final once cast.
+    Immutable,
+    /// `change_vote` replaces the ballot until the poll closes.
+    #[default]
+    Changeable,
+    /// Like `Changeable`, and `revoke_vote` withdraws the ballot.
+    Revocable,
+}
+
+impl VoteChangePolicy {
+    pub(crate) fn allows_change(self) -> bool {
+        self != VoteChangePolicy::Immutable
+    }
+}
+
+/// Who may list a poll's individual ballots through `get_votes`. Voters can
+/// always read their own ballot with `get_my_vote`.
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum BallotVisibility {
+    #[default]
+    Public,
+    /// Only the poll's managers.
+    CreatorOnly,
+    /// No one: only the results are published.
+    AggregateOnly,
+}
+
+/// When callers other than the poll's managers may see its tallies and
+/// ballots. Everyone may once the poll has closed.
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum ResultsVisibility {
+    #[default]
+    Always,
+    /// Only callers who have voted.
+    AfterVoting,
+    AfterClose,
+}
+
+/// How voters choose among a poll's options.
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum VotingMode {
+    /// Each ballot selects exactly one option; the most selected option wins.
+    #[default]
+    SingleChoice,
+    /// Approval voting: each ballot selects between `min_choices` and
+    /// `max_choices` distinct options and the most approved option wins.
+    MultipleChoice { min_choices: u32, max_choices: u32 },
+    /// Each ballot ranks some or all options, most preferred first. Results
+    /// are computed from the full ballots when the poll closes.
+    Ranked { method: RankingMethod },
+    /// Each ballot spreads votes over distinct options, paying the square of
+    /// the votes given to each option out of `budget` voice credits. When
+    /// `pps_backed`, a voter's budget is further capped by the PPS they have
+    /// locked for the poll in the configured `pps_token` canister.
+    Quadratic { budget: u64, pps_backed: bool },
+    /// A numeric answer from `min` to `max` in increments of `step`, such as
+    /// a 1-5 Likert item, a 0-10 NPS score or a slider. Takes no options.
+    Scale { min: i64, max: i64, step: u64 },
+}
+
+impl VotingMode {
+    /// Inclusive bounds on the number of options a ballot selects.
+    fn choice_bounds(self, options: usize) -> (u32, u32) {
+        match self {
+            VotingMode::SingleChoice => (1, 1),
+            VotingMode::MultipleChoice { min_choices, max_choices } => (min_choices, max_choices),
+            VotingMode::Ranked { .. } => (1, options as u32),
+            VotingMode::Quadratic { .. } => (1, options.min(MAX_CHOICES as usize) as u32),
+            VotingMode::Scale { .. } => (0, 0),
+        }
+    }
+
+    pub(crate) fn takes_options(self) -> bool {
+        !matches!(self, VotingMode::Scale { .. })
+    }
+
+    /// Number of counters in the poll's `Tally`: one per option, or one per
+    /// allowed value of a scale.
+    pub(crate) fn tally_width(self, options: usize) -> usize {
+        match self {
+            VotingMode::Scale { min, max, step } => scale_points(min, max, step) as usize,
+            _ => options,
+        }
+    }
+
+    pub(crate) fn is_ranked(self) -> bool {
+        matches!(self, VotingMode::Ranked { .. })
+    }
+
+    pub(crate) fn is_pps_backed(self) -> bool {
+        matches!(self, VotingMode::Quadratic { pps_backed: true, .. })
+    }
+
+    /// What a ballot adds to each counter of the running tally: the first
+    /// preference of a ranking, the votes given in a quadratic ballot, the
+    /// value of a scale answer, one per selection otherwise; the last two
+    /// count `Vote::weight` times.
+    pub(crate) fn counted(self, vote: &Vote) -> Vec<(u32, u64)> {
+        let choices = vote.choices.iter();
+        match self {
+            VotingMode::Ranked { .. } => choices.take(1).map(|&choice| (choice, 1)).collect(),
+            VotingMode::Quadratic { .. } => choices
+                .zip(&vote.votes)
+                .map(|(&choice, &votes)| (choice, votes as u64))
+                .collect(),
+            VotingMode::Scale { min, step, .. } => vote.value
+                .map(|value| (value.abs_diff(min) / step) as u32)
+                .map(|point| (point, vote.weight))
+                .into_iter()
+                .collect(),
+            _ => choices.map(|&choice| (choice, vote.weight)).collect(),
+        }
+    }
+
+    pub(crate) fn is_stv(self) -> bool {
+        self == VotingMode::Ranked { method: RankingMethod::SingleTransferableVote }
+    }
+
+    /// Checks that a poll with `options` options electing `seats` seats can be
+    /// voted on in this mode.
+    pub(crate) fn validate(self, options: usize, seats: u32) -> Result<(), PollError> {
+        let (min, max) = self.choice_bounds(options);
+        let reason = if matches!(self, VotingMode::Quadratic { budget: 0, .. }) {
+            "budget must be positive"
+        } else if seats == 0 {
+            "seats must be at least 1"
+        } else if seats > 1 && !self.is_stv() {
+            "only single transferable vote polls elect more than one seat"
+        } else if seats as usize >= options && self.is_stv() {
+            "seats must be fewer than the number of options"
+        } else if let VotingMode::Scale { min, max, step } = self {
+            if step == 0 {
+                "step must be positive"
+            } else if min >= max {
+                "min must be below max"
+            } else if max.abs_diff(min) % step != 0 {
+                "the range must be a whole number of steps"
+            } else if scale_points(min, max, step) > MAX_SCALE_POINTS {
+                "the scale has too many steps"
+            } else {
+                return Ok(());
+            }
+        } else if self.is_ranked() && options > MAX_CHOICES as usize {
+            "too many options for a ranked poll"
+        } else if min == 0 {
+            "min_choices must be at least 1"
+        } else if min > max {
+            "min_choices must not exceed max_choices"
+        } else if max as usize > options {
+            "max_choices must not exceed the number of options"
+        } else if max > MAX_CHOICES {
+            "max_choices is too large"
+        } else {
+            return Ok(());
+        };
+
+        Err(PollError::InvalidVotingMode { reason: reason.to_string() })
+    }
+
+    /// Checks that `vote` is a valid ballot for a poll with `options` options.
+    /// The credit cost of quadratic ballots is checked separately, against
+    /// the voter's budget.
+    pub(crate) fn validate_ballot(self, options: usize, vote: &Vote) -> Result<(), PollError> {
+        let invalid = |reason: &str| Err(PollError::InvalidBallot { reason: reason.to_string() });
+        let (choices, votes) = (&vote.choices, &vote.votes);
+
+        if let VotingMode::Scale { min, max, step } = self {
+            let Some(value) = vote.value else {
+                return invalid("scale polls take a value");
+            };
+            if !choices.is_empty() || !votes.is_empty() {
+                return invalid("scale polls take a value, not choices");
+            }
+            if value < min || value > max || value.abs_diff(min) % step != 0 {
+                return Err(PollError::InvalidValue { min, max, step });
+            }
+            return Ok(());
+        }
+        if vote.value.is_some() {
+            return invalid("values are only used by scale polls");
+        }
+
+        let (min, max) = self.choice_bounds(options);
+        if choices.len() < min as usize || choices.len() > max as usize {
+            return Err(PollError::InvalidChoiceCount { min, max });
+        }
+        for (index, &choice) in choices.iter().enumerate() {
+            if choice as usize >= options {
+                let max = (options as u32).saturating_sub(1);
+                return Err(PollError::InvalidOption { max });
+            }
+            if choices[..index].contains(&choice) {
+                return Err(PollError::DuplicateChoice { index: index as u32 });
+            }
+        }
+
+        match self {
+            VotingMode::Quadratic { .. } if votes.len() != choices.len() => {
+                invalid("votes must give a count for each choice")
+            }
+            VotingMode::Quadratic { .. } if votes.contains(&0) => invalid("votes must be positive"),
+            VotingMode::Quadratic { .. } => Ok(()),
+            _ if !votes.is_empty() => invalid("votes are only used by quadratic polls"),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn scale_points(min: i64, max: i64, step: u64) -> u64 {
+    max.abs_diff(min) / step.max(1) + 1
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct ScaleBucket {
+    value: i64,
+    count: u64,
+}
+
+/// Distribution of the answers to a scale poll. The statistics are 0 before
+/// the first answer.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct ScaleStats {
+    /// One bucket per allowed value, in ascending order.
+    histogram: Vec<ScaleBucket>,
+    mean: f64,
+    median: f64,
+    /// Population standard deviation.
+    std_dev: f64,
+}
+
+impl ScaleStats {
+    /// Computes the statistics from the per-value `counts` of a scale
+    /// starting at `min` with increments of `step`.
+    pub(crate) fn from_counts(min: i64, step: u64, counts: &[u64]) -> Self {
+        let histogram: Vec<ScaleBucket> = counts.iter()
+            .enumerate()
+            .map(|(point, &count)| ScaleBucket {
+                value: min + (point as u64 * step) as i64,
+                count,
+            })
+            .collect();
+
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return ScaleStats { histogram, mean: 0.0, median: 0.0, std_dev: 0.0 };
+        }
+        let n = total as f64;
+        let mean = histogram.iter().map(|bucket| bucket.value as f64 * bucket.count as f64).sum::<f64>() / n;
+        let variance = histogram.iter()
+            .map(|bucket| (bucket.value as f64 - mean).powi(2) * bucket.count as f64)
+            .sum::<f64>() / n;
+
+        // Value of the `rank`-th answer (from 0) in ascending order.
+        let nth = |rank: u64| {
+            let mut seen = 0;
+            histogram.iter()
+                .find(|bucket| {
+                    seen += bucket.count;
+                    seen > rank
+                })
+                .map_or(0, |bucket| bucket.value) as f64
+        };
+        let median = if total % 2 == 1 {
+            nth(total / 2)
+        } else {
+            (nth(total / 2 - 1) + nth(total / 2)) / 2.0
+        };
+
+        ScaleStats { histogram, mean, median, std_dev: variance.sqrt() }
+    }
+}
+
+/// Voice credits a quadratic ballot costs: the sum of squared votes.
+pub(crate) fn quadratic_cost(votes: &[u32]) -> u64 {
+    votes.iter().map(|&votes| votes as u64 * votes as u64).sum()
+}
+
+/// Indices of the options with the highest count: several on a tie, none
+/// before anything has been counted.
+pub(crate) fn leaders(counts: &[u64]) -> Vec<u32> {
+    let top = counts.iter().copied().max().unwrap_or(0);
+    if top == 0 {
+        return Vec::new();
+    }
+    (0..counts.len() as u32)
+        .filter(|&index| counts[index as usize] == top)
+        .collect()
+}
+
+/// One round of an instant-runoff count.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RunoffRound {
+    /// Ballots counting for each option this round; zero once eliminated.
+    counts: Vec<u64>,
+    /// Ballots with no remaining preference.
+    exhausted: u64,
+    /// Options eliminated at the end of this round.
+    eliminated: Vec<u32>,
+}
+
+/// Tallies of a ranked poll, computed once when it closes.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub(crate) struct RankedResults {
+    pub(crate) method: RankingMethod,
+    pub(crate) rounds: Vec<RunoffRound>,
+    /// An option ranked `i`-th (from 0) on a ballot scores `options - 1 - i`.
+    pub(crate) borda_scores: Vec<u64>,
+    /// Winners under `method`, several on a tie.
+    pub(crate) winners: Vec<u32>,
+}
+
+impl Versioned for RankedResults {
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 128 * 1024; // 128KB, a full trace for MAX_CHOICES options
+}
+
+impl RankedResults {
+    /// `ballots` pairs each distinct ranking with the number of voters who
+    /// cast it; `election` is the STV count of the same ballots, for STV polls.
+    pub(crate) fn compute(
+        method: RankingMethod,
+        options: usize,
+        ballots: &[(Vec<u32>, u64)],
+        election: Option<&ElectionReport>,
+    ) -> Self {
+        let (rounds, runoff_winners) = instant_runoff(options, ballots);
+        let borda_scores = borda(options, ballots);
+        let winners = match method {
+            RankingMethod::InstantRunoff => runoff_winners,
+            RankingMethod::Borda => leaders(&borda_scores),
+            RankingMethod::SingleTransferableVote => {
+                election.map(|report| report.elected.clone()).unwrap_or_default()
+            }
+        };
+        RankedResults { method, rounds, borda_scores, winners }
+    }
+}
+
+/// Counts `ballots` by instant runoff. Each round every ballot counts for its
+/// highest-ranked remaining option; an option with a majority of the counted
+/// ballots wins, otherwise all options tied for the fewest ballots are
+/// eliminated together. If every remaining option is tied they all win.
+fn instant_runoff(options: usize, ballots: &[(Vec<u32>, u64)]) -> (Vec<RunoffRound>, Vec<u32>) {
+    let mut eliminated = vec![false; options];
+    let mut rounds = Vec::new();
+
+    loop {
+        let mut counts = vec![0u64; options];
+        let mut exhausted = 0;
+        for (ballot, voters) in ballots {
+            match ballot.iter().find(|&&choice| !eliminated[choice as usize]) {
+                Some(&choice) => counts[choice as usize] += voters,
+                None => exhausted += voters,
+            }
+        }
+
+        let remaining: Vec<u32> = (0..options as u32)
+            .filter(|&option| !eliminated[option as usize])
+            .collect();
+        let counted: u64 = counts.iter().sum();
+        let most = remaining.iter().map(|&option| counts[option as usize]).max().unwrap_or(0);
+        let fewest = remaining.iter().map(|&option| counts[option as usize]).min().unwrap_or(0);
+
+        let winners = if counted == 0 {
+            Some(Vec::new())
+        } else if most * 2 > counted {
+            Some(remaining.iter().copied().filter(|&option| counts[option as usize] == most).collect())
+        } else if most == fewest {
+            Some(remaining.clone())
+        } else {
+            None
+        };
+        if let Some(winners) = winners {
+            rounds.push(RunoffRound { counts, exhausted, eliminated: Vec::new() });
+            return (rounds, winners);
+        }
+
+        let losers: Vec<u32> = remaining.into_iter()
+            .filter(|&option| counts[option as usize] == fewest)
+            .collect();
+        for &option in &losers {
+            eliminated[option as usize] = true;
+        }
+        rounds.push(RunoffRound { counts, exhausted, eliminated: losers });
+    }
+}
+
+fn borda(options: usize, ballots: &[(Vec<u32>, u64)]) -> Vec<u64> {
+    let mut scores = vec![0u64; options];
+    for (ballot, voters) in ballots {
+        for (rank, &choice) in ballot.iter().enumerate() {
+            scores[choice as usize] += (options - 1 - rank) as u64 * voters;
+        }
+    }
+    scores
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instant_runoff_transfers_eliminated_preferences() {
+        // First preferences 3/1/1/2 with no majority: 1 and 2 are eliminated
+        // together, one of their ballots transfers to 3 and the other runs
+        // out, leaving 0 and 3 tied.
+        let ballots = [(vec![0], 3), (vec![1], 1), (vec![2, 3], 1), (vec![3], 2)];
+        let (rounds, winners) = instant_runoff(4, &ballots);
+
+        assert_eq!(rounds.len(), 2);
+        assert_eq!(rounds[0].counts, vec![3, 1, 1, 2]);
+        assert_eq!(rounds[0].eliminated, vec![1, 2]);
+        assert_eq!(rounds[1].counts, vec![3, 0, 0, 3]);
+        assert_eq!(rounds[1].exhausted, 1);
+        assert_eq!(winners, vec![0, 3]);
+    }
+
+    fn ballot(choices: &[u32]) -> Vote {
+        Vote {
+            poll_id: 1,
+            voter: Principal::anonymous(),
+            choices: choices.to_vec(),
+            votes: Vec::new(),
+            value: None,
+            voted_at: 0,
+            revision: 0,
+            weight: 1,
+        }
+    }
+
+    #[test]
+    fn multiple_choice_ballots_stay_within_bounds() {
+        let mode = |min_choices, max_choices| VotingMode::MultipleChoice { min_choices, max_choices };
+        assert_eq!(mode(1, 3).validate(3, 1), Ok(()));
+        for (min, max) in [(0, 2), (3, 2), (1, 4)] {
+            assert!(matches!(mode(min, max).validate(3, 1), Err(PollError::InvalidVotingMode { .. })));
+        }
+
+        let approval = mode(2, 3);
+        assert_eq!(approval.validate_ballot(4, &ballot(&[3, 0])), Ok(()));
+        let counts = PollError::InvalidChoiceCount { min: 2, max: 3 };
+        assert_eq!(approval.validate_ballot(4, &ballot(&[1])), Err(counts.clone()));
+        assert_eq!(approval.validate_ballot(4, &ballot(&[0, 1, 2, 3])), Err(counts));
+        assert_eq!(approval.validate_ballot(4, &ballot(&[0, 4])), Err(PollError::InvalidOption { max: 3 }));
+        let repeated = approval.validate_ballot(4, &ballot(&[2, 1, 2]));
+        assert_eq!(repeated, Err(PollError::DuplicateChoice { index: 2 }));
+
+        // Every approved option counts once.
+        assert_eq!(approval.counted(&ballot(&[3, 0])), vec![(3, 1), (0, 1)]);
+    }
+
+    #[test]
+    fn scale_stats_summarize_the_histogram() {
+        // NPS answers: 0, 7, 7, 9, 10, 10.
+        let mut counts = vec![0; 11];
+        counts[0] = 1;
+        counts[7] = 2;
+        counts[9] = 1;
+        counts[10] = 2;
+
+        let stats = ScaleStats::from_counts(0, 1, &counts);
+        assert_eq!(stats.histogram.len(), 11);
+        assert_eq!(stats.histogram[7], ScaleBucket { value: 7, count: 2 });
+        assert_eq!(stats.mean, 43.0 / 6.0);
+        assert_eq!(stats.median, 8.0);
+        assert!((stats.std_dev - 3.4359).abs() < 1e-4);
+
+        let likert = ScaleStats::from_counts(-2, 2, &[0, 3, 0]);
+        assert_eq!(likert.histogram[1].value, 0);
+        assert_eq!((likert.mean, likert.median, likert.std_dev), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn borda_scores_partial_rankings() {
+        let ballots = [(vec![0, 1, 2], 1), (vec![1, 0], 1), (vec![1], 1)];
+        let results = RankedResults::compute(RankingMethod::Borda, 3, &ballots, None);
+
+        assert_eq!(results.borda_scores, vec![3, 5, 0]);
+        assert_eq!(results.winners, vec![1]);
+        assert_eq!(results.rounds.last().unwrap().counts, vec![1, 2, 0]);
+    }
+}
@@ -1,47 +1,35 @@
 use candid::{CandidType, Deserialize, Principal};
-use ic_cdk::api::call::CallResult;
 use ic_cdk::api::time;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use pollrush_common::stable::{decode_payload, rekey, Envelope, LegacyPrincipalKey, PrincipalKey, Versioned};
+use pollrush_common::PollError;
 use serde::Serialize;
 use std::cell::RefCell;
-use std::borrow::Cow;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
-#[derive(CandidType, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
-struct PrincipalKey(Principal);
-
-impl Default for PrincipalKey {
-    fn default() -> Self {
-        // Use the anonymous principal as default
-        PrincipalKey(Principal::anonymous())
-    }
-}
-
-impl Storable for PrincipalKey {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(bincode::serialize(self).unwrap())
-    }
-
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        bincode::deserialize(&bytes).unwrap()
-    }
-}
-
-impl BoundedStorable for PrincipalKey {
-    const MAX_SIZE: u32 = 29; // Principal is 29 bytes
-    const IS_FIXED_SIZE: bool = true;
-}
-
 thread_local! {
+    // The canister's stable memory. Natively this is an in-memory buffer, which
+    // lets tests snapshot it and replay an upgrade on a fresh heap.
+    static STABLE_MEMORY: DefaultMemoryImpl = DefaultMemoryImpl::default();
+
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
+        MemoryManager::init(STABLE_MEMORY.with(|memory| memory.clone()))
     );
 
-    static BALANCES: RefCell<StableBTreeMap<PrincipalKey, Balance, Memory>> = RefCell::new(
+    // Moved from memory 0 when principal keys took their current encoding;
+    // see `post_upgrade`.
+    static BALANCES: RefCell<StableBTreeMap<PrincipalKey, Envelope<Balance>, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)))
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+        )
+    );
+
+    // PPS set aside by an owner for one poll, keyed by (owner, poll id).
+    static LOCKS: RefCell<StableBTreeMap<(PrincipalKey, u64), Envelope<Lock>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
         )
     );
 }
@@ -52,19 +40,64 @@ struct Balance {
     last_updated: u64,
 }
 
-impl Storable for Balance {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(bincode::serialize(self).unwrap())
+/// Size of a balance written before records were versioned: two bincode
+/// `u64`s and no version byte.
+const UNVERSIONED_BALANCE_SIZE: usize = 16;
+
+impl Versioned for Balance {
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 1023; // 1KB with the version byte, like unversioned balances
+
+    fn decode_unversioned(bytes: &[u8]) -> Option<Result<Self, PollError>> {
+        (bytes.len() == UNVERSIONED_BALANCE_SIZE).then(|| decode_payload(0, bytes))
     }
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        bincode::deserialize(&bytes).unwrap()
+impl Balance {
+    fn empty(now: u64) -> Self {
+        Balance {
+            amount: 0,
+            last_updated: now,
+        }
     }
 }
 
-impl BoundedStorable for Balance {
-    const MAX_SIZE: u32 = 1024; // 1KB
-    const IS_FIXED_SIZE: bool = false;
+fn load_balance(account: Principal) -> Result<Option<Balance>, PollError> {
+    BALANCES.with(|balances| balances.borrow().get(&PrincipalKey(account)))
+        .map(|balance| balance.decode())
+        .transpose()
+}
+
+fn store_balance(account: Principal, balance: &Balance) {
+    BALANCES.with(|balances| {
+        balances.borrow_mut().insert(PrincipalKey(account), Envelope::new(balance));
+    });
+}
+
+/// PPS taken out of an owner's balance to back their voting credits in a poll.
+#[derive(CandidType, Deserialize, Clone, Serialize)]
+struct Lock {
+    amount: u64,
+    /// The lock cannot be released before this time.
+    until: u64,
+}
+
+impl Versioned for Lock {
+    const VERSION: u8 = 1;
+    const MAX_SIZE: u32 = 64;
+}
+
+fn load_lock(owner: Principal, poll_id: u64) -> Result<Option<Lock>, PollError> {
+    LOCKS.with(|locks| locks.borrow().get(&(PrincipalKey(owner), poll_id)))
+        .map(|lock| lock.decode())
+        .transpose()
+}
+
+#[derive(CandidType, Deserialize)]
+struct LockArgs {
+    poll_id: u64,
+    amount: u64,
+    until: u64,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -73,68 +106,252 @@ struct TransferArgs {
     amount: u64,
 }
 
+/// Balance of `account`; an account whose record cannot be decoded reads as
+/// empty here, while `transfer` and `mint` report it as an error.
 #[ic_cdk::query]
 fn balance_of(account: Principal) -> u64 {
-    BALANCES.with(|balances| {
-        balances.borrow()
-            .get(&PrincipalKey(account))
-            .map(|balance| balance.amount)
-            .unwrap_or(0)
-    })
+    load_balance(account)
+        .ok()
+        .flatten()
+        .map(|balance| balance.amount)
+        .unwrap_or(0)
 }
 
 #[ic_cdk::update]
-fn transfer(args: TransferArgs) -> Result<(), String> {
-    let caller = ic_cdk::caller();
+fn transfer(args: TransferArgs) -> Result<(), PollError> {
+    transfer_from(ic_cdk::caller(), args, time())
+}
+
+fn transfer_from(caller: Principal, args: TransferArgs, now: u64) -> Result<(), PollError> {
     let amount = args.amount;
-    
-    BALANCES.with(|balances| {
-        let mut balances = balances.borrow_mut();
-        let from_balance = balances.get(&PrincipalKey(caller)).unwrap_or(Balance {
-            amount: 0,
-            last_updated: time(),
-        });
-        
-        if from_balance.amount < amount {
-            return Err("Insufficient balance".to_string());
-        }
-        
-        let to_balance = balances.get(&PrincipalKey(args.to)).unwrap_or(Balance {
-            amount: 0,
-            last_updated: time(),
-        });
-        
-        balances.insert(PrincipalKey(caller), Balance {
-            amount: from_balance.amount - amount,
-            last_updated: time(),
-        });
-        
-        balances.insert(PrincipalKey(args.to), Balance {
-            amount: to_balance.amount + amount,
-            last_updated: time(),
-        });
-        
-        Ok(())
-    })
+
+    let from_balance = load_balance(caller)?.unwrap_or(Balance::empty(now));
+    if from_balance.amount < amount {
+        return Err(PollError::InsufficientBalance { available: from_balance.amount });
+    }
+
+    let to_balance = load_balance(args.to)?.unwrap_or(Balance::empty(now));
+    if args.to != caller && to_balance.amount.checked_add(amount).is_none() {
+        return Err(PollError::BalanceOverflow);
+    }
+
+    store_balance(caller, &Balance {
+        amount: from_balance.amount - amount,
+        last_updated: now,
+    });
+
+    // Re-read so a self-transfer credits the debited balance.
+    let to_balance = if args.to == caller {
+        load_balance(args.to)?.unwrap_or(to_balance)
+    } else {
+        to_balance
+    };
+    store_balance(args.to, &Balance {
+        amount: to_balance.amount + amount,
+        last_updated: now,
+    });
+
+    Ok(())
 }
 
+/// Credits `amount` new PPS to `account`; controllers only.
 #[ic_cdk::update]
-fn mint(account: Principal, amount: u64) -> Result<(), String> {
-    let _caller = ic_cdk::caller();
-    // TODO: Add authorization check
-    
+fn mint(account: Principal, amount: u64) -> Result<(), PollError> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(PollError::NotAuthorized);
+    }
+
+    credit(account, amount, time())
+}
+
+/// Moves `amount` from the caller's balance into their lock for `poll_id`.
+/// Locking again for the same poll adds to the lock and keeps the later
+/// `until`.
+#[ic_cdk::update]
+fn lock(args: LockArgs) -> Result<(), PollError> {
+    lock_for(ic_cdk::caller(), args, time())
+}
+
+/// Returns the caller's lock for `poll_id` to their balance once it has
+/// expired, and the amount released.
+#[ic_cdk::update]
+fn unlock(poll_id: u64) -> Result<u64, PollError> {
+    release(ic_cdk::caller(), poll_id, time())
+}
+
+#[ic_cdk::query]
+fn get_lock(owner: Principal, poll_id: u64) -> Option<Lock> {
+    load_lock(owner, poll_id).ok().flatten()
+}
+
+fn lock_for(owner: Principal, args: LockArgs, now: u64) -> Result<(), PollError> {
+    let balance = load_balance(owner)?.unwrap_or(Balance::empty(now));
+    if balance.amount < args.amount {
+        return Err(PollError::InsufficientBalance { available: balance.amount });
+    }
+    let lock = match load_lock(owner, args.poll_id)? {
+        Some(lock) => Lock {
+            amount: lock.amount.checked_add(args.amount).ok_or(PollError::BalanceOverflow)?,
+            until: lock.until.max(args.until),
+        },
+        None => Lock { amount: args.amount, until: args.until },
+    };
+
+    store_balance(owner, &Balance {
+        amount: balance.amount - args.amount,
+        last_updated: now,
+    });
+    LOCKS.with(|locks| {
+        locks.borrow_mut().insert((PrincipalKey(owner), args.poll_id), Envelope::new(&lock));
+    });
+    Ok(())
+}
+
+fn release(owner: Principal, poll_id: u64, now: u64) -> Result<u64, PollError> {
+    let Some(lock) = load_lock(owner, poll_id)? else {
+        return Ok(0);
+    };
+    if now < lock.until {
+        return Err(PollError::StillLocked { until: lock.until });
+    }
+
+    credit(owner, lock.amount, now)?;
+    LOCKS.with(|locks| locks.borrow_mut().remove(&(PrincipalKey(owner), poll_id)));
+    Ok(lock.amount)
+}
+
+/// Moves balances written under the bincode key encoding, a `u64` length and
+/// the bytes, into their current memory. Later upgrades find the old map empty.
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    let legacy = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)));
     BALANCES.with(|balances| {
-        let mut balances = balances.borrow_mut();
-        let current_balance = balances.get(&PrincipalKey(account)).unwrap_or(Balance {
-            amount: 0,
-            last_updated: time(),
-        });
-        
-        balances.insert(PrincipalKey(account), Balance {
-            amount: current_balance.amount + amount,
-            last_updated: time(),
-        });
-        
-        Ok(())
-    })
+        rekey(legacy, &mut balances.borrow_mut(), |LegacyPrincipalKey(owner)| PrincipalKey(owner));
+    });
+}
+
+fn credit(account: Principal, amount: u64, now: u64) -> Result<(), PollError> {
+    let current_balance = load_balance(account)?.unwrap_or(Balance::empty(now));
+    let amount = current_balance.amount.checked_add(amount).ok_or(PollError::BalanceOverflow)?;
+
+    store_balance(account, &Balance {
+        amount,
+        last_updated: now,
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::Storable;
+    use std::borrow::Cow;
+
+    #[test]
+    fn balances_survive_upgrade() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        credit(alice, 250, 1).unwrap();
+        credit(bob, 40, 2).unwrap();
+        credit(alice, 50, 3).unwrap();
+
+        let snapshot = STABLE_MEMORY.with(|memory| memory.borrow().clone());
+        std::thread::spawn(move || {
+            STABLE_MEMORY.with(|memory| *memory.borrow_mut() = snapshot);
+            post_upgrade();
+
+            assert_eq!(balance_of(alice), 300);
+            assert_eq!(balance_of(bob), 40);
+            assert_eq!(balance_of(Principal::from_slice(&[3])), 0);
+        })
+        .join()
+        .expect("post-upgrade checks failed");
+    }
+
+    #[test]
+    fn baseline_balances_move_to_the_current_key_encoding() {
+        let alice = Principal::from_slice(&[1]);
+        let long = Principal::from_slice(&[9; 21]);
+        let memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)));
+        let mut legacy: StableBTreeMap<LegacyPrincipalKey, Envelope<Balance>, Memory> =
+            StableBTreeMap::init(memory);
+        for (owner, amount) in [(alice, 75u64), (long, 5)] {
+            // Unversioned, as written alongside the old keys.
+            let bytes = bincode::serialize(&Balance { amount, last_updated: 9 }).unwrap();
+            legacy.insert(LegacyPrincipalKey(owner), Envelope::from_bytes(Cow::Owned(bytes)));
+        }
+
+        let snapshot = STABLE_MEMORY.with(|memory| memory.borrow().clone());
+        std::thread::spawn(move || {
+            STABLE_MEMORY.with(|memory| *memory.borrow_mut() = snapshot);
+            post_upgrade();
+
+            assert_eq!((balance_of(alice), balance_of(long)), (75, 5));
+            credit(alice, 25, 10).unwrap();
+            post_upgrade();
+            assert_eq!(balance_of(alice), 100);
+        })
+        .join()
+        .expect("post-upgrade checks failed");
+    }
+
+    #[test]
+    fn locks_hold_balance_until_expiry() {
+        let alice = Principal::from_slice(&[1]);
+        credit(alice, 100, 1).unwrap();
+
+        lock_for(alice, LockArgs { poll_id: 7, amount: 60, until: 50 }, 2).unwrap();
+        lock_for(alice, LockArgs { poll_id: 7, amount: 10, until: 40 }, 3).unwrap();
+        assert!(matches!(
+            lock_for(alice, LockArgs { poll_id: 8, amount: 31, until: 50 }, 4),
+            Err(PollError::InsufficientBalance { available: 30 })
+        ));
+        assert_eq!(balance_of(alice), 30);
+        assert_eq!(get_lock(alice, 7).map(|lock| (lock.amount, lock.until)), Some((70, 50)));
+
+        assert!(matches!(release(alice, 7, 49), Err(PollError::StillLocked { until: 50 })));
+        assert_eq!(release(alice, 7, 50), Ok(70));
+        assert_eq!(release(alice, 7, 51), Ok(0));
+        assert_eq!(balance_of(alice), 100);
+    }
+
+    #[test]
+    fn credits_past_the_largest_amount_are_refused() {
+        let alice = Principal::from_slice(&[1]);
+        credit(alice, u64::MAX - 10, 1).unwrap();
+        assert_eq!(credit(alice, 11, 2), Err(PollError::BalanceOverflow));
+        assert_eq!(balance_of(alice), u64::MAX - 10);
+
+        let lock = |amount| LockArgs { poll_id: 7, amount, until: 50 };
+        lock_for(alice, lock(u64::MAX - 20), 3).unwrap();
+        credit(alice, 20, 4).unwrap();
+        assert_eq!(lock_for(alice, lock(21), 5), Err(PollError::BalanceOverflow));
+        assert_eq!(balance_of(alice), 30);
+
+        let bob = Principal::from_slice(&[2]);
+        credit(bob, u64::MAX, 5).unwrap();
+        let to_bob = |amount| TransferArgs { to: bob, amount };
+        assert_eq!(transfer_from(alice, to_bob(1), 6), Err(PollError::BalanceOverflow));
+        assert_eq!((balance_of(alice), balance_of(bob)), (30, u64::MAX));
+        transfer_from(bob, TransferArgs { to: bob, amount: u64::MAX }, 6).unwrap();
+
+        // A lock that no longer fits the balance stays locked.
+        credit(alice, u64::MAX - 30, 6).unwrap();
+        assert_eq!(release(alice, 7, 50), Err(PollError::BalanceOverflow));
+        assert_eq!(get_lock(alice, 7).map(|lock| lock.amount), Some(u64::MAX - 20));
+    }
+
+    #[test]
+    fn unversioned_balances_still_decode() {
+        // Stored under the same bound as before balances were versioned.
+        assert_eq!(<Envelope<Balance> as ic_stable_structures::BoundedStorable>::MAX_SIZE, 1024);
+        let legacy = bincode::serialize(&Balance { amount: 75, last_updated: 9 }).unwrap();
+        let stored = Envelope::<Balance>::from_bytes(Cow::Owned(legacy));
+
+        let balance = stored.decode().unwrap();
+        assert_eq!((balance.amount, balance.last_updated), (75, 9));
+
+        let garbage = Envelope::<Balance>::from_bytes(Cow::Owned(vec![1, 2, 3]));
+        assert!(matches!(garbage.decode(), Err(PollError::DecodeFailed { version: 1, .. })));
+    }
 } 
\ No newline at end of file